@@ -7,7 +7,35 @@ export const enum SupportedEncoding {
   Cl100k = 0,
   Llama3 = 1,
   O200k = 2,
-  Codestral = 3
+  Codestral = 3,
+  /** The encoding loaded by `Tokenizer.fromTiktokenFile`. Only valid with that tokenizer. */
+  Custom = 4,
+  R50k = 5,
+  P50k = 6,
+  Gpt2 = 7
+}
+/**
+ * The kind of a failure. Every error the tokenizer throws has a message that starts with its
+ * kind, like `"ActorDead: Actor task has been killed"`, so callers can branch on the kind without
+ * matching the rest of the message.
+ */
+export const enum TokenizerErrorKind {
+  /** The text could not be encoded or the tokens could not be decoded. Retrying won't help. */
+  EncodingError = 'EncodingError',
+  /** A token id or token bytes the encoding doesn't know. */
+  UnknownToken = 'UnknownToken',
+  /** The actor handling the request went away before responding. Retrying may succeed. */
+  ActorDead = 'ActorDead',
+  /** The tokenizer has been closed. */
+  ChannelClosed = 'ChannelClosed',
+  /** An argument is invalid, like an unknown model or a missing custom encoding. */
+  InvalidArgument = 'InvalidArgument',
+  /** The queue was full, see `try_encode`. */
+  Overloaded = 'Overloaded',
+  /** The request was cancelled, see `encode_cancellable`. */
+  Cancelled = 'Cancelled',
+  /** No response arrived in time, see `encode_with_timeout`. Retrying may succeed. */
+  TimedOut = 'TimedOut'
 }
 export const enum SpecialTokenAction {
   /** The special token is forbidden. If it is included in the string, an error will be returned. */
@@ -17,18 +45,665 @@ export const enum SpecialTokenAction {
   /** The special token is treated as the special token it is. If this is applied to a specific text and the text is NOT a special token then an error will be returned. If it is the default action no error will be returned, don't worry. */
   Special = 2
 }
+export interface Chunk {
+  text: string
+  numTokens: number
+}
+export interface ChatMessage {
+  role: string
+  content: string
+  name?: string
+}
+export interface TokenOffset {
+  token: number
+  /** Byte offset into the text where the token starts. */
+  start: number
+  /** Byte offset into the text where the token ends, exclusive. */
+  end: number
+}
+export interface TokenEstimate {
+  estimate: number
+  /** The exact count is expected to be at least this. */
+  lower: number
+  /** The exact count is expected to be at most this, but the bound is not measured. */
+  upper: number
+}
+/** How long loading each built-in encoding took, in milliseconds. */
+export interface LoadTimings {
+  cl100KMs: number
+  llama3Ms: number
+  o200KMs: number
+  codestralMs: number
+  r50KMs: number
+  p50KMs: number
+  gpt2Ms: number
+}
+/** One part of a multimodal chat message content, like `{ type: "text", text: "..." }`. */
+export interface ContentPart {
+  type: string
+  text?: string
+}
+export interface EncodeResult {
+  count: number
+  /** Only set if the tokens were asked for. */
+  tokens?: Array<number>
+}
+export interface PoolStats {
+  /** Messages waiting for an actor, across all priority lanes. */
+  queued: number
+  /** How many messages can wait before callers have to wait too. */
+  capacity: number
+  /** Actor threads that haven't been joined by `close`. */
+  actorCount: number
+  /**
+   * Messages waiting in the actors' own lanes, see `encode_with_affinity`. These are not part of
+   * `queued` and `capacity`.
+   */
+  affinityQueued: number
+}
+export interface ActorHealth {
+  /** Whether the actor thread is still running. */
+  running: boolean
+  /** How many times the actor was restarted after a message made it panic. */
+  restarts: number
+}
+export interface TokenOffset16 {
+  token: number
+  /** Offset into the text in UTF-16 code units, like JS string indices, where the token starts. */
+  start: number
+  /** Offset into the text in UTF-16 code units where the token ends, exclusive. */
+  end: number
+}
+export interface CountComparison {
+  exact: number
+  approx: number
+  /** `|exact - approx|`. */
+  absError: number
+  /** `exact / approx`, with `approx` taken to be at least 1. */
+  ratio: number
+}
+export interface EncodingInfo {
+  name: string
+  /** The number of tokens, including the special tokens. */
+  vocabSize: number
+  numSpecialTokens: number
+  /** Whether `encode_with_bos_eos` can add a beginning of text token. */
+  hasBos: boolean
+  /** Whether `encode_with_bos_eos` can add an end of text token. */
+  hasEos: boolean
+}
+export interface TokenPiece {
+  token: number
+  /** The bytes the token stands for, which need not be valid UTF-8 on their own. */
+  bytes: Uint8Array
+}
+export interface PackResult {
+  includedIndices: Array<number>
+  totalTokens: number
+  /** The included snippets joined by the separator. */
+  text: string
+}
+export interface BenchResult {
+  totalTokens: number
+  elapsedMs: number
+  tokensPerSec: number
+}
+export interface BatchStats {
+  counts: Array<number>
+  total: number
+  /** 0 for an empty batch, like `min` and `mean`. */
+  max: number
+  min: number
+  mean: number
+}
 export declare function getTokenizer(): Tokenizer
+/**
+ * Whether the given encoding is available in this build. Every encoding is currently compiled in,
+ * so this only returns false if loading the encoding failed. Loads the encoding if needed.
+ */
+export declare function hasEncoding(encoding: SupportedEncoding): boolean
+/** Resolves a model name like `gpt-4o-mini-2024-07-18` to its encoding by longest prefix match. */
+export declare function encodingForModel(model: string): SupportedEncoding
+/**
+ * Loads the built-in encodings if that hasn't happened yet, so the first real request doesn't pay
+ * for it, and returns how long each one took to load. Later calls return the same timings.
+ */
+export declare function preloadEncodings(): LoadTimings
+/**
+ * Loads just the given built-in encodings if they aren't loaded yet, so the first requests using
+ * them don't pay for it. Safe to call repeatedly.
+ */
+export declare function preload(encodings: Array<SupportedEncoding>): void
 export class Tokenizer {
+  /**
+   * Creates a tokenizer whose actors serve `num_priority_lanes` lanes, see
+   * `encode_with_priority`. A single lane behaves exactly like `get_tokenizer()`.
+   */
+  static withPriorityLanes(numPriorityLanes: number): Tokenizer
+  /**
+   * Creates a tokenizer with `num_threads` actor threads (at least 1) instead of the default 4.
+   * Every actor still handles one message at a time, so more threads only help when there are
+   * that many requests in flight concurrently.
+   */
+  static withThreadCount(numThreads: number): Tokenizer
+  /**
+   * Creates a tokenizer with `num_threads` actor threads (at least 1) whose queue holds
+   * `queue_capacity` messages (default 256) before calls wait for an actor to free up. A deeper
+   * queue absorbs bursts, a shallow one pushes back on callers sooner, and makes `try_encode`
+   * report `Overloaded` sooner. Every actor's own lane for `encode_with_affinity` holds
+   * `queue_capacity` messages on top of that.
+   */
+  static withConfig(numThreads: number, queueCapacity?: number | undefined | null): Tokenizer
+  /**
+   * Creates a tokenizer that can also encode with the rank file at `path`, which has a base64
+   * encoded token and its rank on every line, using `SupportedEncoding.Custom`. `pattern` is the
+   * regex used to split text before merging.
+   */
+  static fromTiktokenFile(path: string, specialTokens: Record<string, number>, pattern: string): Tokenizer
+  /**
+   * Lets the actor threads finish the messages that are already queued, then resolves once they
+   * have exited. Every call made afterwards fails with a "Tokenizer has been closed" error. This
+   * closes every clone of the tokenizer, so don't call it on the shared `getTokenizer()` instance.
+   */
+  close(): Promise<void>
   exactNumTokensNoSpecialTokens(text: string, encoding: SupportedEncoding): Promise<number>
-  exactNumTokens(text: string, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>): Promise<number>
+  /**
+   * If `strip_control_chars` is set, the C0 control characters U+0000 through U+001F are removed
+   * before encoding, except for tab (U+0009) and line feed (U+000A). Defaults to false.
+   *
+   * Overrides for strings that are not special tokens of the encoding are an error, unless
+   * `ignore_unknown_overrides` is set.
+   */
+  exactNumTokens(text: string, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>, stripControlChars?: boolean | undefined | null, ignoreUnknownOverrides?: boolean | undefined | null): Promise<number>
   encodeCl100KNoSpecialTokens(text: string): Promise<Array<number>>
   approxNumTokens(text: string, encoding: SupportedEncoding, replaceSpacesWithLowerOneEighthBlock: boolean): Promise<number>
-  encode(text: string, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>): Promise<Array<number>>
+  /**
+   * Like `approx_num_tokens`, but also returns bounds on the exact count from a fixed ratio per
+   * encoding. Short texts get a few tokens of extra slack. The ratios are unvalidated guesses
+   * rather than measured error, so don't rely on `upper` where going over it is unsafe.
+   */
+  approxNumTokensWithBounds(text: string, encoding: SupportedEncoding, replaceSpacesWithLowerOneEighthBlock: boolean): Promise<TokenEstimate>
+  /**
+   * If `strip_control_chars` is set, the C0 control characters U+0000 through U+001F are removed
+   * before encoding, except for tab (U+0009) and line feed (U+000A). Defaults to false.
+   *
+   * If `max_special_tokens` is set, an error is returned when the encoded text contains more
+   * special tokens than that.
+   *
+   * Overrides for strings that are not special tokens of the encoding are an error, unless
+   * `ignore_unknown_overrides` is set.
+   */
+  encode(text: string, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>, stripControlChars?: boolean | undefined | null, maxSpecialTokens?: number | undefined | null, ignoreUnknownOverrides?: boolean | undefined | null): Promise<Array<number>>
   encodeSingleToken(bytes: Uint8Array, encoding: SupportedEncoding): Promise<number>
   decodeByte(token: number, encoding: SupportedEncoding): Promise<Uint8Array>
   decode(encodedTokens: Array<number>, encoding: SupportedEncoding): Promise<string>
+  /**
+   * Encodes the text with every loaded encoding, keyed by encoding name. Special tokens are
+   * treated as normal text.
+   */
+  encodeAll(text: string): Promise<Record<string, Uint32Array>>
+  /**
+   * Counts the tokens of the added and removed lines of a unified diff, ignoring file headers,
+   * hunk headers and context lines. Returns an error if the diff is malformed, including any
+   * non-empty text without a hunk header.
+   */
+  countDiffChanges(unifiedDiff: string, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Encodes the text and returns the token ids joined by commas. Special tokens are treated as
+   * normal text.
+   */
+  encodeToCsv(text: string, encoding: SupportedEncoding): Promise<string>
+  /** Decodes comma-separated token ids, as produced by `encode_to_csv`. */
+  decodeFromCsv(csv: string, encoding: SupportedEncoding): Promise<string>
+  /**
+   * Counts the tokens of the standard (padded) base64 encoding of the bytes, which is what
+   * inlining them in a data URI costs.
+   */
+  numTokensBase64(bytes: Uint8Array, encoding: SupportedEncoding): Promise<number>
+  /** Returns the number of bytes the tokens decode to, without building the decoded string. */
+  decodeByteLen(tokens: Array<number>, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Like `encode`, but the message jumps ahead of any queued messages with a lower priority.
+   * Priorities go from 0 (the lane every other method uses) up to the number of lanes minus one,
+   * higher ones are clamped. To avoid starving the lower lanes, every eighth message an actor picks
+   * up is taken from the lowest non-empty lane instead.
+   *
+   * Unknown overrides are an error unless `ignore_unknown_overrides` is set, like in `encode`.
+   */
+  encodeWithPriority(text: string, encoding: SupportedEncoding, priority: number, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>, ignoreUnknownOverrides?: boolean | undefined | null): Promise<Array<number>>
+  /**
+   * Returns the number of tokens divided by the number of characters (unicode scalar values, not
+   * bytes), or 0 for an empty text. Special tokens are treated as normal text.
+   */
+  tokensPerChar(text: string, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Groups the sentences of the text into chunks of at most `max_tokens` tokens each. Sentences end
+   * at `.`, `!` or `?` followed by whitespace, and are only split if a single sentence is over the
+   * limit. Special tokens are treated as normal text.
+   *
+   * A chunk's `num_tokens` is the sum of the counts of its sentences, which can differ slightly
+   * from encoding the chunk as a whole.
+   */
+  splitTokensBySentence(text: string, encoding: SupportedEncoding, maxTokens: number): Promise<Array<Chunk>>
+  /**
+   * Returns the distinct tokens of the text, sorted ascending. Special tokens are treated as normal
+   * text.
+   */
+  uniqueTokens(text: string, encoding: SupportedEncoding): Promise<Array<number>>
+  /** Returns the number of distinct tokens of the text. Special tokens are treated as normal text. */
+  uniqueTokenCount(text: string, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Whether the bytes the encoded text decodes to are exactly the bytes of the text. Special
+   * tokens are treated as normal text.
+   */
+  isLossless(text: string, encoding: SupportedEncoding): Promise<boolean>
+  /**
+   * Encodes all texts in a single actor message, in order. Fails if any of them fails, with the
+   * index of the offending text in the error. Unknown overrides are an error unless
+   * `ignore_unknown_overrides` is set, like in `encode`.
+   */
+  encodeBatch(texts: Array<string>, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>, ignoreUnknownOverrides?: boolean | undefined | null): Promise<Array<Array<number>>>
+  /**
+   * Counts the tokens of all texts in a single actor message, in order. Fails if any of them
+   * fails, with the index of the offending text in the error. Unknown overrides are an error
+   * unless `ignore_unknown_overrides` is set, like in `exact_num_tokens`.
+   */
+  exactNumTokensBatch(texts: Array<string>, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>, ignoreUnknownOverrides?: boolean | undefined | null): Promise<Array<number>>
+  /**
+   * Counts the tokens an OpenAI chat request with these messages costs, including the
+   * per-message framing and the reply priming. Only supported for cl100k and o200k.
+   */
+  numTokensForChat(messages: Array<ChatMessage>, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Like `num_tokens_for_chat`, but with the framing overhead given by the caller: every message
+   * costs `tokens_per_message` on top of its role and content, a name costs `tokens_per_name` on
+   * top of the name itself, and the reply priming costs `reply_priming`. Works with any encoding.
+   */
+  numTokensForChatCustom(messages: Array<ChatMessage>, encoding: SupportedEncoding, tokensPerMessage: number, tokensPerName: number, replyPriming: number): Promise<number>
+  /**
+   * Truncates the text to its first (or last, if `from_end`) `max_tokens` tokens. Text that already
+   * fits is returned unchanged, and the cut never splits a character, so the result can be a few
+   * tokens shorter than `max_tokens`. Special tokens are treated as normal text.
+   */
+  truncateToTokens(text: string, maxTokens: number, encoding: SupportedEncoding, fromEnd: boolean): Promise<string>
+  /**
+   * Encodes the text and returns every token with the byte range of the text it covers. A token
+   * that only covers part of a multi-byte character gets the range of the whole character. Special
+   * tokens are treated as normal text.
+   */
+  encodeWithOffsets(text: string, encoding: SupportedEncoding): Promise<Array<TokenOffset>>
+  /**
+   * Splits the text into chunks of `chunk_size` tokens that overlap by `overlap` tokens; the last
+   * chunk may be shorter. Chunks are slices of the text, so a chunk boundary inside a multi-byte
+   * character includes the whole character. Special tokens are treated as normal text.
+   */
+  chunkByTokens(text: string, chunkSize: number, overlap: number, encoding: SupportedEncoding): Promise<Array<string>>
+  /** The number of tokens in the encoding, including the special tokens. */
+  vocabSize(encoding: SupportedEncoding): number
+  /** The special token strings of the encoding, sorted. */
+  specialTokens(encoding: SupportedEncoding): Array<string>
+  /**
+   * Decodes the tokens, replacing invalid UTF-8 (like a sequence that ends mid-character) with
+   * U+FFFD instead of failing. If `replacement` is set, it is used instead of U+FFFD; the empty
+   * string drops invalid bytes altogether.
+   */
+  decodeLossy(encodedTokens: Array<number>, encoding: SupportedEncoding, replacement?: string | undefined | null): Promise<string>
+  /** Decodes all token sequences in a single actor message, in order. */
+  decodeBatch(sequences: Array<Array<number>>, encoding: SupportedEncoding): Promise<Array<string>>
+  /**
+   * Like `encode` with special tokens treated as normal text, but fails with "Encoding was
+   * cancelled" once `token.cancel()` is called. Large texts are encoded in chunks of about 64KiB
+   * that end at a newline or before a space, and cancellation is checked between chunks.
+   */
+  encodeCancellable(text: string, encoding: SupportedEncoding, token: CancellationToken): Promise<Array<number>>
+  /**
+   * Returns whether the text is at least `limit` tokens long, with special tokens treated as
+   * normal text. Encoding stops at the first chunk (see `encode_cancellable`) that reaches the
+   * limit, so large texts are not tokenized in full.
+   */
+  numTokensAtLeast(text: string, encoding: SupportedEncoding, limit: number): Promise<boolean>
+  /**
+   * Encodes the text like `encode_cancellable` and calls `callback(null, tokens, false)` with the
+   * tokens of every chunk as soon as it is encoded. The last call is `callback(null, [], true)`
+   * once the whole text is done, or `callback(err)` if encoding failed.
+   */
+  encodeStreaming(text: string, encoding: SupportedEncoding, callback: (err: Error | null, tokens: Array<number>, done: boolean) => void): void
+  /**
+   * Same result as `encode` with special tokens treated as normal text, but large texts are split
+   * into chunks at boundaries no merge can cross (see `encode_cancellable`) and the chunks are
+   * encoded concurrently by all actors. Texts without such boundaries are encoded by one actor.
+   */
+  encodeParallel(text: string, encoding: SupportedEncoding): Promise<Array<number>>
+  /**
+   * Counts the tokens of the text parts of a multimodal message content, with special tokens
+   * treated as normal text. Parts of any other type, like `image_url`, count as 0 tokens for now,
+   * since what an image costs depends on the model and its detail setting.
+   */
+  numTokensForContentParts(parts: Array<ContentPart>, encoding: SupportedEncoding): Promise<number>
+  /**
+   * The raw bytes of the tokens, concatenated. Unlike `decode` this keeps a character that is
+   * split across the end of the sequence intact, so streamed completions can be joined exactly.
+   */
+  decodeBytes(tokens: Array<number>, encoding: SupportedEncoding): Promise<Uint8Array>
+  /**
+   * Encodes the text with special tokens treated as normal text and returns the token count,
+   * plus the tokens themselves if `include_tokens` is set. Leaving them out saves converting a
+   * large array to JS when only the count is needed.
+   */
+  encodeAndCount(text: string, encoding: SupportedEncoding, includeTokens: boolean): Promise<EncodeResult>
+  /**
+   * Like `decode`, but fails naming the first token (and its index) that the encoding doesn't
+   * have, neither as a regular nor as a special token, instead of decoding garbage.
+   */
+  decodeChecked(tokens: Array<number>, encoding: SupportedEncoding): Promise<string>
+  /** A cheap snapshot of the actor pool's queue, read without sending a message. */
+  poolStats(): PoolStats
+  /**
+   * Like `encode` with special tokens treated as normal text, but fails right away with
+   * "Tokenizer overloaded" when the queue is full instead of waiting for room, so callers can
+   * shed load.
+   */
+  tryEncode(text: string, encoding: SupportedEncoding): Promise<Array<number>>
+  /** The regex the encoding splits text with before applying BPE, as tiktoken has it. */
+  splitPattern(encoding: SupportedEncoding): string
+  /**
+   * Splits the text into the pieces the encoding's split regex produces, without merging them
+   * into tokens. Special tokens are not recognized. The pieces concatenate to the text.
+   */
+  pretokenize(text: string, encoding: SupportedEncoding): Promise<Array<string>>
+  /**
+   * Adds the token count of `appended_text`, with special tokens treated as normal text, to the
+   * count of the text before it, without recounting that text. Only exact if appending doesn't
+   * change how the end of the previous text tokenizes, which holds when `appended_text` starts
+   * with whitespace after a non-whitespace character, such as a new message on its own line.
+   */
+  numTokensIncremental(previousCount: number, appendedText: string, encoding: SupportedEncoding): Promise<number>
+  /**
+   * The health of each actor thread, in spawn order. Actors restart themselves after a panic, so a
+   * panicking actor shows up as a growing `restarts` count while `running` stays true; `running`
+   * is only false for a thread that is gone for good. Empty after `close`.
+   */
+  actorHealth(): Array<ActorHealth>
+  /**
+   * Counts the tokens of the UTF-8 file at `path`, with special tokens treated as normal text.
+   * The actor reads the file in pieces, so its contents never have to go through JS.
+   */
+  numTokensInFile(path: string, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Like `encode_with_offsets`, but the offsets are in UTF-16 code units, so they can be passed
+   * straight to `String.prototype.slice`. Characters outside the BMP, like most emoji, count as
+   * two units.
+   */
+  encodeWithUtf16Offsets(text: string, encoding: SupportedEncoding): Promise<Array<TokenOffset16>>
+  /**
+   * Whether the text, with special tokens treated as normal text, fits in the context window of
+   * the model, like `gpt-4o` or `gpt-4-0314`. The fast estimate decides unless it is within 10% of
+   * the window, in which case the tokens are counted exactly. Fails for unknown models.
+   */
+  fitsInModel(text: string, model: string): Promise<boolean>
+  /**
+   * Encodes the text with special tokens treated as normal text, then adds the encoding's
+   * beginning of text token in front if `add_bos` is set and its end of text token at the end if
+   * `add_eos` is set. Fails if the encoding has no such token, like cl100k which has no BOS.
+   */
+  encodeWithBosEos(text: string, encoding: SupportedEncoding, addBos: boolean, addEos: boolean): Promise<Array<number>>
+  /**
+   * Like `truncate_to_tokens` keeping the start of the text, but if the text has to be cut, the
+   * cut is moved back to the end of the last sentence (a `.`, `!` or `?` followed by whitespace)
+   * in the kept text. A sentence that ends right at the cut is kept. Falls back to the plain cut
+   * if the kept text has no sentence end.
+   */
+  truncateToTokensSentenceAware(text: string, maxTokens: number, encoding: SupportedEncoding): Promise<string>
+  /**
+   * The number of leading tokens the two sequences have in common. Works on ids alone, so it
+   * doesn't take an encoding.
+   */
+  commonPrefixLen(a: Array<number>, b: Array<number>): Promise<number>
+  /**
+   * Like `decode`, but drops the encoding's special tokens, like `<|im_start|>`, first. The rest
+   * is decoded together, so a character split across the tokens around a special token is kept
+   * whole.
+   */
+  decodeWithoutSpecial(tokens: Array<number>, encoding: SupportedEncoding): Promise<string>
+  /**
+   * Whether `decode(encode(text))` gives back the text, with special tokens treated as normal
+   * text. A mismatch returns false rather than failing, so callers can log the offending input.
+   * Since the text is valid UTF-8, this is the same check as `is_lossless`.
+   */
+  verifyRoundtrip(text: string, encoding: SupportedEncoding): Promise<boolean>
+  /**
+   * How often every token occurs across all texts, with special tokens treated as normal text.
+   * JS object keys are strings, so the token ids are the keys in decimal.
+   */
+  tokenHistogram(texts: Array<string>, encoding: SupportedEncoding): Promise<Record<string, number>>
+  /**
+   * Like `encode` with special tokens treated as normal text, but if
+   * `replace_spaces_with_lower_one_eighth_block` is set, every space is first replaced by U+2581
+   * (▁), like `approx_num_tokens` does with the same flag. That changes the tokens, so this is only
+   * meant for calibrating the estimate against exact counts, never for feeding a model.
+   */
+  encodeWithSpaceReplacement(text: string, encoding: SupportedEncoding, replaceSpacesWithLowerOneEighthBlock?: boolean | undefined | null): Promise<Array<number>>
+  /**
+   * Counts the tokens exactly, with special tokens treated as normal text, and estimates them
+   * like `approx_num_tokens`, on the same actor in one message.
+   */
+  compareCounts(text: string, encoding: SupportedEncoding): Promise<CountComparison>
+  /**
+   * Like `encode` with special tokens treated as normal text, but fails with "Tokenizer timed
+   * out" if the actors haven't responded within `timeout_ms` after the request was queued.
+   */
+  encodeWithTimeout(text: string, encoding: SupportedEncoding, timeoutMs: number): Promise<Array<number>>
+  /** The name, vocabulary size and special token counts of the encoding in one cheap read. */
+  encodingInfo(encoding: SupportedEncoding): EncodingInfo
+  /**
+   * Encodes the text, with special tokens treated as normal text, and pairs every token with the
+   * bytes it stands for.
+   */
+  encodeWithPieces(text: string, encoding: SupportedEncoding): Promise<Array<TokenPiece>>
+  /**
+   * Counts the tokens of the text, with special tokens treated as normal text, after deleting
+   * every occurrence of each string in `remove`, longest first.
+   */
+  numTokensAfterRemoving(text: string, remove: Array<string>, encoding: SupportedEncoding): Promise<number>
+  /**
+   * The indices of the texts that are at most `max_tokens` tokens long, with special tokens
+   * treated as normal text. Texts stop being encoded once they go over, like in
+   * `num_tokens_at_least`.
+   */
+  filterUnderBudget(texts: Array<string>, maxTokens: number, encoding: SupportedEncoding): Promise<Array<number>>
+  /**
+   * Takes snippets from the front of the list, joined by `separator`, until the next one would
+   * take the total over `max_tokens`. The separator only counts between snippets. Snippets and
+   * separators are counted separately, with special tokens treated as normal text, so the count of
+   * the joined text can differ slightly where they meet.
+   */
+  packUnderBudget(snippets: Array<string>, maxTokens: number, separator: string, encoding: SupportedEncoding): Promise<PackResult>
+  /**
+   * Counts the tokens in the text, with special tokens treated as normal text, but stops at the
+   * first chunk (see `encode_cancellable`) that reaches `cap`. The result is exactly `cap` when
+   * the text has at least `cap` tokens, and the exact count otherwise.
+   */
+  numTokensCapped(text: string, encoding: SupportedEncoding, cap: number): Promise<number>
+  /**
+   * Like `encode` with special tokens treated as normal text, but applies Unicode NFC
+   * normalization first, so decomposed characters like "e" followed by a combining acute accent
+   * are encoded as the composed "é". This can give a different number of tokens than encoding the
+   * text as is, so only use it where the other side normalizes too.
+   */
+  encodeNfc(text: string, encoding: SupportedEncoding): Promise<Array<number>>
+  /**
+   * The cost of the text's tokens, counted like `exact_num_tokens_no_special_tokens`, at
+   * `price_per_1k_tokens` per 1000 tokens.
+   */
+  estimateCost(text: string, encoding: SupportedEncoding, pricePer1KTokens: number): Promise<number>
+  /**
+   * The cost of a chat request: the prompt, counted like `num_tokens_for_chat`, at
+   * `input_price_per_1k` per 1000 tokens, plus `output_token_estimate` reply tokens at
+   * `output_price_per_1k` per 1000 tokens. Only supported for cl100k and o200k.
+   */
+  estimateChatCost(messages: Array<ChatMessage>, encoding: SupportedEncoding, inputPricePer1K: number, outputTokenEstimate: number, outputPricePer1K: number): Promise<number>
+  /**
+   * Decodes every token on its own, for showing tokens side by side. A token that is only part
+   * of a character decodes to U+FFFD instead of failing, so joining the pieces doesn't always
+   * give the same text as `decode`.
+   */
+  decodeToPieces(tokens: Array<number>, encoding: SupportedEncoding): Promise<Array<string>>
+  /**
+   * Like `encode` with special tokens treated as normal text, but takes the text as UTF-8 bytes,
+   * like the contents of a file read into a `Buffer`, without converting them to a string first.
+   * Fails with `InvalidArgument` if the bytes are not valid UTF-8.
+   */
+  encodeBytes(bytes: Uint8Array, encoding: SupportedEncoding): Promise<Array<number>>
+  /**
+   * Encodes the sample `iterations` times, one request after the other, with special tokens
+   * treated as normal text, and reports the throughput. Every request goes through the actor queue
+   * like any other call, so the numbers include the messaging overhead.
+   */
+  benchmark(sampleText: string, encoding: SupportedEncoding, iterations: number): Promise<BenchResult>
+  /**
+   * Counts the tokens of all texts in a single actor message, with special tokens treated as
+   * normal text, and aggregates the counts.
+   */
+  batchTokenStats(texts: Array<string>, encoding: SupportedEncoding): Promise<BatchStats>
+  /**
+   * Adds special tokens, like `{ "<|tool_call|>": 100300 }`, to a built-in encoding. Neither the
+   * strings nor the ids may already be in the encoding's vocabulary.
+   *
+   * This changes the encoding for every tokenizer in the process, including `getTokenizer()`,
+   * and only works before the encoding is first used, so call it once at startup.
+   */
+  addSpecialTokens(encoding: SupportedEncoding, tokens: Record<string, number>): void
+  /**
+   * Counts the tokens of every line in a single actor message, with special tokens treated as
+   * normal text. Lines are split on `\n`, which is not counted, and empty lines count 0.
+   */
+  lineTokenCounts(text: string, encoding: SupportedEncoding): Promise<Array<number>>
+  /**
+   * Like `encode` with special tokens treated as normal text, but returns a `Uint32Array`, which
+   * is much cheaper to hand to JS than a plain array for long texts.
+   */
+  encodeTyped(text: string, encoding: SupportedEncoding): Promise<Uint32Array>
+  /** Like `decode`, but takes the tokens as a `Uint32Array`, like the ones `encode_typed` returns. */
+  decodeTyped(tokens: Uint32Array, encoding: SupportedEncoding): Promise<string>
+  /**
+   * The index of the first token, with special tokens treated as normal text, whose text covers
+   * the character at `char_offset`, given in UTF-16 code units like a JS string index. Returns -1
+   * if the offset is past the end of the text.
+   */
+  tokenIndexAtChar(text: string, charOffset: number, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Like `encode_single_token` for every byte string, in a single actor message. Byte strings
+   * that are not a single token give -1 instead of failing the whole batch.
+   */
+  encodeSingleTokensBatch(byteStrings: Array<Uint8Array>, encoding: SupportedEncoding): Promise<Array<number>>
+  /**
+   * Counts the tokens of the template, with special tokens treated as normal text, after
+   * replacing every `{{key}}` placeholder with `substitutions[key]`. Fails if a placeholder has
+   * no substitution.
+   */
+  numTokensTemplate(template: string, substitutions: Record<string, string>, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Counts the tokens of the template with every placeholder left out, i.e. the fixed cost that
+   * every filled in version of it shares, up to a token or two where text meets a substitution.
+   */
+  numTokensTemplateStatic(template: string, encoding: SupportedEncoding): Promise<number>
+  /**
+   * Like `encode` with special tokens treated as normal text, but every call with the same
+   * `affinity_key` is handled by the same actor, whose caches are then warm for text it has seen
+   * before, like a long shared prompt prefix. The message waits for that actor even when others
+   * are idle, so spread unrelated work over different keys.
+   */
+  encodeWithAffinity(text: string, encoding: SupportedEncoding, affinityKey: number): Promise<Array<number>>
+  /**
+   * Counts the tokens of the text with every built-in encoding in a single actor message, keyed
+   * by encoding name. Special tokens are treated as normal text.
+   */
+  numTokensAllEncodings(text: string): Promise<Record<string, number>>
+  /**
+   * The number of BPE merges encoding the text takes, with special tokens treated as normal text.
+   * Every merge joins two parts into one, starting from one part per byte, so this is exactly the
+   * number of bytes minus the number of tokens, without instrumenting the BPE loop.
+   */
+  numMerges(text: string, encoding: SupportedEncoding): Promise<number>
+}
+/** Cancels the `Tokenizer.encodeCancellable` calls it is passed to. */
+export class CancellationToken {
+  constructor()
+  cancel(): void
+  get isCancelled(): boolean
+}
+/**
+ * Decodes tokens one at a time as a model emits them, without decoding the whole output again
+ * for every token. Runs on the calling thread.
+ */
+export class StreamDecoder {
+  constructor(encoding: SupportedEncoding)
+  /**
+   * Adds the token and returns the text it completes. A character split across tokens is held
+   * back until its last token arrives, so the returned pieces concatenate to the full output.
+   */
+  push(token: number): string
+  /** Returns the rest of a character that was never completed, as U+FFFD, and resets the decoder. */
+  finish(): string
 }
 export class SyncTokenizer {
   constructor()
+  /**
+   * Like the constructor, but loads every built-in encoding up front and throws if one of them
+   * fails to load, e.g. because its vocab file is missing, instead of failing on first use.
+   */
+  static tryNew(): SyncTokenizer
   approxNumTokens(text: string, encoding: SupportedEncoding): number
+  /** Like `Tokenizer.encode`, but runs on the calling thread instead of the actor pool. */
+  encode(text: string, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>, ignoreUnknownOverrides?: boolean | undefined | null): Array<number>
+  /** Like `Tokenizer.decode`, but runs on the calling thread instead of the actor pool. */
+  decode(tokens: Array<number>, encoding: SupportedEncoding): string
+  /** The number of tokens in the encoding, including the special tokens. */
+  vocabSize(encoding: SupportedEncoding): number
+  /** The special token strings of the encoding, sorted. */
+  specialTokens(encoding: SupportedEncoding): Array<string>
+  /** Whether the token id is one of the encoding's special tokens. */
+  isSpecialToken(token: number, encoding: SupportedEncoding): boolean
+  /**
+   * The token's bytes as a string, with invalid UTF-8 replaced by U+FFFD. Never throws: tokens the
+   * encoding doesn't know, and encodings this tokenizer can't use, give `"<invalid>"`.
+   */
+  tokenToStringLossy(token: number, encoding: SupportedEncoding): string
+  /** Like `Tokenizer.exact_num_tokens`, but runs on the calling thread instead of the actor pool. */
+  exactNumTokens(text: string, encoding: SupportedEncoding, specialTokenDefaultAction: SpecialTokenAction, specialTokenOverrides: Record<string, SpecialTokenAction>, ignoreUnknownOverrides?: boolean | undefined | null): number
+  /**
+   * Like `approx_num_tokens`, with the same `replace_spaces_with_lower_one_eighth_block` flag as
+   * `Tokenizer.approx_num_tokens`, so both give the same estimate.
+   */
+  approxNumTokensSync(text: string, encoding: SupportedEncoding, replaceSpacesWithLowerOneEighthBlock: boolean): number
+  /**
+   * The BPE merge rank of the token, which for tiktoken encodings is the token id itself. Fails
+   * for special tokens, which are not merged, and for unknown tokens.
+   */
+  tokenRank(token: number, encoding: SupportedEncoding): number
+  /** The number of bytes the token decodes to. */
+  byteLengthOfToken(token: number, encoding: SupportedEncoding): number
+  /**
+   * Guesses which of the candidate encodings produced the tokens, by counting the tokens each one
+   * knows and decodes to valid UTF-8 on its own. Ties go to the earlier candidate. This is a
+   * heuristic: encodings that share most of their vocabulary, like cl100k and o200k for common
+   * English, can't be told apart from a short sample.
+   */
+  guessEncoding(sampleTokens: Array<number>, candidates: Array<SupportedEncoding>): SupportedEncoding
+  /**
+   * Splits the text into chunks of roughly `target_tokens` tokens each, going by the fast
+   * estimate instead of running BPE, so it is much cheaper than exact chunking but chunks can be
+   * well off the target. Chunks end after whitespace where possible and concatenate to the text.
+   */
+  approxChunkByTokens(text: string, targetTokens: number, encoding: SupportedEncoding): Array<string>
+  /**
+   * The length in bytes of the encoding's longest token, including the special tokens, so no
+   * single token decodes to more than this. Computed on the first call for each encoding.
+   */
+  maxTokenByteLength(encoding: SupportedEncoding): number
+  /**
+   * Whether the text literally contains one of the encoding's special tokens, like
+   * `<|endoftext|>`. Only scans for the strings, without encoding the text.
+   */
+  containsSpecialLiteral(text: string, encoding: SupportedEncoding): boolean
 }
@@ -252,10 +252,17 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { SupportedEncoding, Tokenizer, SpecialTokenAction, SyncTokenizer, getTokenizer } = nativeBinding
+const { SupportedEncoding, TokenizerErrorKind, Tokenizer, SpecialTokenAction, CancellationToken, StreamDecoder, SyncTokenizer, getTokenizer, hasEncoding, encodingForModel, preloadEncodings, preload } = nativeBinding
 
 module.exports.SupportedEncoding = SupportedEncoding
+module.exports.TokenizerErrorKind = TokenizerErrorKind
 module.exports.Tokenizer = Tokenizer
 module.exports.SpecialTokenAction = SpecialTokenAction
+module.exports.CancellationToken = CancellationToken
+module.exports.StreamDecoder = StreamDecoder
 module.exports.SyncTokenizer = SyncTokenizer
 module.exports.getTokenizer = getTokenizer
+module.exports.hasEncoding = hasEncoding
+module.exports.encodingForModel = encodingForModel
+module.exports.preloadEncodings = preloadEncodings
+module.exports.preload = preload
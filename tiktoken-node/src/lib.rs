@@ -1,30 +1,44 @@
 use anyhow::Context;
-use async_channel::{bounded, Receiver, Sender};
+use async_channel::{bounded, unbounded, Receiver, RecvError, SendError, Sender, TrySendError};
+use base64::Engine;
 use napi::bindgen_prelude::create_custom_tokio_runtime;
 use napi::bindgen_prelude::Error;
+use napi::threadsafe_function::{
+  ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, JsObject};
 use napi_derive::napi;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
+use rustc_hash::FxHashSet;
 use tiktoken::EncodingFactoryError;
 use tokio::runtime::Builder;
+use unicode_normalization::UnicodeNormalization;
 
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::io::Read;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 // we use the actor pattern to have good cache locality
 // this means that no tokenization requests will ever run in parallel, but i think that's almost certainly fine
 use napi::tokio::sync::oneshot;
 
-static TOKENIZER: Lazy<Result<Tokenizer, Error>> =
-  Lazy::new(|| Tokenizer::new().map_err(|e| Error::from_reason(e.to_string())));
+static TOKENIZER: Lazy<Result<Tokenizer, Error>> = Lazy::new(Tokenizer::new);
 
-static ENCODINGS: Lazy<Result<Arc<Encodings>, EncodingFactoryError>> = Lazy::new(|| {
-  Ok(Arc::new(Encodings {
-    cl100k_encoding: tiktoken::EncodingFactory::cl100k_im()?,
-    llama3_encoding: tiktoken::EncodingFactory::llama3()?,
-    o200k_encoding: tiktoken::EncodingFactory::o200k_im()?,
-    codestral_encoding: tiktoken::EncodingFactory::codestral()?,
-  }))
-});
+static ENCODINGS: Lazy<Arc<Encodings>> = Lazy::new(|| Arc::new(Encodings::new()));
+
+/// Runs an encoding factory and measures how long it took in milliseconds.
+fn load_timed(
+  factory: fn() -> Result<tiktoken::Encoding, EncodingFactoryError>,
+) -> Result<(tiktoken::Encoding, f64), EncodingFactoryError> {
+  let start = Instant::now();
+  let encoding = factory()?;
+  Ok((encoding, start.elapsed().as_secs_f64() * 1000.0))
+}
 
 #[napi]
 pub enum SupportedEncoding {
@@ -32,18 +46,493 @@ pub enum SupportedEncoding {
   Llama3 = 1,
   O200k = 2,
   Codestral = 3,
+  /// The encoding loaded by `Tokenizer.fromTiktokenFile`. Only valid with that tokenizer.
+  Custom = 4,
+  R50k = 5,
+  P50k = 6,
+  Gpt2 = 7,
+}
+
+impl SupportedEncoding {
+  const ALL: [SupportedEncoding; 7] = [
+    SupportedEncoding::Cl100k,
+    SupportedEncoding::Llama3,
+    SupportedEncoding::O200k,
+    SupportedEncoding::Codestral,
+    SupportedEncoding::R50k,
+    SupportedEncoding::P50k,
+    SupportedEncoding::Gpt2,
+  ];
+
+  fn name(&self) -> &'static str {
+    match self {
+      SupportedEncoding::Cl100k => "cl100k_base",
+      SupportedEncoding::Llama3 => "llama3",
+      SupportedEncoding::O200k => "o200k_base",
+      SupportedEncoding::Codestral => "codestral",
+      SupportedEncoding::Custom => "custom",
+      SupportedEncoding::R50k => "r50k_base",
+      SupportedEncoding::P50k => "p50k_base",
+      SupportedEncoding::Gpt2 => "gpt2",
+    }
+  }
+
+  /// The special token that marks the beginning of a text, for encodings that have one.
+  fn bos_token(&self) -> Option<&'static str> {
+    match self {
+      SupportedEncoding::Llama3 => Some("<|begin_of_text|>"),
+      SupportedEncoding::Codestral => Some("<s>"),
+      _ => None,
+    }
+  }
+
+  /// The special token that marks the end of a text, for encodings that have one.
+  fn eos_token(&self) -> Option<&'static str> {
+    match self {
+      SupportedEncoding::Llama3 => Some("<|end_of_text|>"),
+      SupportedEncoding::Codestral => Some("</s>"),
+      SupportedEncoding::Cl100k
+      | SupportedEncoding::O200k
+      | SupportedEncoding::R50k
+      | SupportedEncoding::P50k
+      | SupportedEncoding::Gpt2 => Some("<|endoftext|>"),
+      SupportedEncoding::Custom => None,
+    }
+  }
+
+  /// Bounds on the ratio of the exact token count to the fast estimate. These are unvalidated
+  /// guesses, not measured on any corpus: they are meant to be wide enough for prose, code and
+  /// non-latin text, with wider bounds for older and unknown encodings as the estimator is tuned on
+  /// the gpt-4 era vocabularies. `test_approx_num_tokens_with_bounds` only checks a few samples.
+  fn estimate_ratio_bounds(&self) -> (f64, f64) {
+    match self {
+      SupportedEncoding::Cl100k | SupportedEncoding::O200k | SupportedEncoding::Llama3 => {
+        (0.6, 1.6)
+      }
+      SupportedEncoding::Codestral
+      | SupportedEncoding::R50k
+      | SupportedEncoding::P50k
+      | SupportedEncoding::Gpt2 => (0.5, 2.0),
+      SupportedEncoding::Custom => (0.25, 4.0),
+    }
+  }
+}
+
+/// The kind of a failure. Every error the tokenizer throws has a message that starts with its
+/// kind, like `"ActorDead: Actor task has been killed"`, so callers can branch on the kind without
+/// matching the rest of the message.
+#[napi(string_enum)]
+pub enum TokenizerErrorKind {
+  /// The text could not be encoded or the tokens could not be decoded. Retrying won't help.
+  EncodingError,
+  /// A token id or token bytes the encoding doesn't know.
+  UnknownToken,
+  /// The actor handling the request went away before responding. Retrying may succeed.
+  ActorDead,
+  /// The tokenizer has been closed.
+  ChannelClosed,
+  /// An argument is invalid, like an unknown model or a missing custom encoding.
+  InvalidArgument,
+  /// The queue was full, see `try_encode`.
+  Overloaded,
+  /// The request was cancelled, see `encode_cancellable`.
+  Cancelled,
+  /// No response arrived in time, see `encode_with_timeout`. Retrying may succeed.
+  TimedOut,
+}
+
+impl TokenizerErrorKind {
+  fn name(&self) -> &'static str {
+    match self {
+      TokenizerErrorKind::EncodingError => "EncodingError",
+      TokenizerErrorKind::UnknownToken => "UnknownToken",
+      TokenizerErrorKind::ActorDead => "ActorDead",
+      TokenizerErrorKind::ChannelClosed => "ChannelClosed",
+      TokenizerErrorKind::InvalidArgument => "InvalidArgument",
+      TokenizerErrorKind::Overloaded => "Overloaded",
+      TokenizerErrorKind::Cancelled => "Cancelled",
+      TokenizerErrorKind::TimedOut => "TimedOut",
+    }
+  }
+
+  /// The error to throw to JS.
+  fn error(self, reason: impl std::fmt::Display) -> Error {
+    Error::from_reason(format!("{}: {}", self.name(), reason))
+  }
+
+  /// An error for an actor to respond with. `Tokenizer::request` throws it with this kind, where
+  /// other actor errors are thrown as `EncodingError`.
+  fn actor_error(self, reason: impl std::fmt::Display) -> anyhow::Error {
+    anyhow::Error::new(ActorError { kind: self, reason: reason.to_string() })
+  }
+}
+
+struct ActorError {
+  kind: TokenizerErrorKind,
+  reason: String,
+}
+
+impl std::fmt::Display for ActorError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.reason)
+  }
+}
+
+impl std::fmt::Debug for ActorError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}: {}", self.kind.name(), self.reason)
+  }
+}
+
+impl std::error::Error for ActorError {}
+
+// the chat framing overhead documented by openai for the cl100k and o200k chat models: every
+// message is wrapped in <|im_start|>{role}<|im_sep|>...<|im_end|>, a name costs one more token and
+// the reply is primed with <|im_start|>assistant<|im_sep|>
+const CHAT_TOKENS_PER_MESSAGE: i32 = 3;
+const CHAT_TOKENS_PER_NAME: i32 = 1;
+const CHAT_REPLY_PRIMING_TOKENS: i32 = 3;
+
+// added to the upper bound of an estimate so that very short texts are covered too
+const ESTIMATE_UPPER_SLACK: i32 = 8;
+
+// the chunked encode path encodes at least this many bytes at a time
+const ENCODE_CHUNK_BYTES: usize = 64 * 1024;
+
+// `fits_in_model` trusts the fast estimate when it is further than this from the context window
+const FITS_ESTIMATE_MARGIN: f64 = 0.1;
+
+// files are counted reading this many bytes at a time
+const FILE_READ_BYTES: usize = 1 << 20;
+
+const DEFAULT_NUM_ACTORS: usize = 4;
+
+// how many messages every lane buffers before sending waits for an actor to take one
+const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+// every this many messages an actor serves its lanes lowest priority first, so that a steady
+// stream of high priority messages can't starve the lower lanes forever
+const STARVATION_AVOIDANCE_INTERVAL: u32 = 8;
+
+/// The sending half of the priority lanes. Every message is put into its lane first and then
+/// announced with a ticket, so an actor holding a ticket is guaranteed to find a message.
+#[derive(Clone)]
+struct LaneSender {
+  lanes: Vec<Sender<TokenizerMessage>>,
+  tickets: Sender<()>,
+  // one lane per actor that only that actor serves, see `send_to_actor`
+  affinity_lanes: Vec<Sender<TokenizerMessage>>,
+}
+
+impl LaneSender {
+  /// Sends a message into the lowest priority lane.
+  async fn send(&self, msg: TokenizerMessage) -> Result<(), SendError<TokenizerMessage>> {
+    self.send_with_priority(msg, 0).await
+  }
+
+  /// Sends a message into the lane for the given priority, where higher is more urgent. Priorities
+  /// above the highest lane are clamped to it.
+  async fn send_with_priority(
+    &self,
+    msg: TokenizerMessage,
+    priority: u8,
+  ) -> Result<(), SendError<TokenizerMessage>> {
+    let lane = (priority as usize).min(self.lanes.len() - 1);
+    self.lanes[lane].send(msg).await?;
+    // the ticket channel is unbounded so this never waits
+    let _ = self.tickets.send(()).await;
+    Ok(())
+  }
+
+  /// Sends a message to the actor picked by `affinity_key` modulo the number of actors, so that
+  /// messages with the same key are always handled by the same actor. It goes ahead of the
+  /// messages in the shared lanes, but waits for the messages already sent to that actor.
+  async fn send_to_actor(
+    &self,
+    affinity_key: usize,
+    msg: TokenizerMessage,
+  ) -> Result<(), SendError<TokenizerMessage>> {
+    self.affinity_lanes[affinity_key % self.affinity_lanes.len()].send(msg).await
+  }
+
+  /// Sends a message into the lowest priority lane, failing instead of waiting if it is full.
+  fn try_send(&self, msg: TokenizerMessage) -> Result<(), TrySendError<TokenizerMessage>> {
+    self.lanes[0].try_send(msg)?;
+    let _ = self.tickets.try_send(());
+    Ok(())
+  }
+
+  /// The number of messages waiting in the shared lanes.
+  fn len(&self) -> usize {
+    self.lanes.iter().map(|lane| lane.len()).sum()
+  }
+
+  /// The number of messages the shared lanes can hold together before senders have to wait.
+  fn capacity(&self) -> usize {
+    self.lanes.iter().filter_map(|lane| lane.capacity()).sum()
+  }
+
+  /// The number of messages waiting in the actors' affinity lanes.
+  fn affinity_len(&self) -> usize {
+    self.affinity_lanes.iter().map(|lane| lane.len()).sum()
+  }
+
+  /// Closes all lanes. Actors still drain the messages that were already sent before they exit.
+  fn close(&self) {
+    for lane in self.lanes.iter().chain(&self.affinity_lanes) {
+      lane.close();
+    }
+    self.tickets.close();
+  }
+}
+
+struct LaneReceiver {
+  lanes: Vec<Receiver<TokenizerMessage>>,
+  tickets: Receiver<()>,
+  received: Cell<u32>,
+  affinity_lane: Receiver<TokenizerMessage>,
+}
+
+impl LaneReceiver {
+  /// Waits for the next message, taking the ones sent to this actor's affinity lane first.
+  async fn recv(&self) -> Result<TokenizerMessage, RecvError> {
+    tokio::select! {
+      biased;
+      // once the affinity lane is closed and drained this branch is disabled
+      Ok(msg) = self.affinity_lane.recv() => Ok(msg),
+      ticket = self.tickets.recv() => ticket.map(|()| self.take_announced()),
+    }
+  }
+
+  /// Takes a message from the shared lanes after receiving its ticket.
+  fn take_announced(&self) -> TokenizerMessage {
+    let received = self.received.get().wrapping_add(1);
+    self.received.set(received);
+    let lowest_first = received % STARVATION_AVOIDANCE_INTERVAL == 0;
+
+    loop {
+      for i in 0..self.lanes.len() {
+        let lane = if lowest_first { i } else { self.lanes.len() - 1 - i };
+        if let Ok(msg) = self.lanes[lane].try_recv() {
+          return msg;
+        }
+      }
+      // another actor took the message we scanned past, but ours has to be in some lane by now
+      std::thread::yield_now();
+    }
+  }
+}
+
+impl Clone for LaneReceiver {
+  fn clone(&self) -> Self {
+    Self {
+      lanes: self.lanes.clone(),
+      tickets: self.tickets.clone(),
+      received: Cell::new(0),
+      affinity_lane: self.affinity_lane.clone(),
+    }
+  }
+}
+
+/// Creates the shared lanes and a receiver for each of `num_actors` actors, which also has the
+/// actor's own affinity lane.
+fn priority_lanes(
+  num_lanes: usize,
+  num_actors: usize,
+  capacity: usize,
+) -> (LaneSender, Vec<LaneReceiver>) {
+  let (senders, receivers): (Vec<_>, Vec<_>) =
+    (0..num_lanes.max(1)).map(|_| bounded(capacity)).unzip();
+  let (affinity_senders, affinity_receivers): (Vec<_>, Vec<_>) =
+    (0..num_actors.max(1)).map(|_| bounded(capacity)).unzip();
+  let (ticket_sender, ticket_receiver) = unbounded();
+  (
+    LaneSender { lanes: senders, tickets: ticket_sender, affinity_lanes: affinity_senders },
+    affinity_receivers
+      .into_iter()
+      .map(|affinity_lane| LaneReceiver {
+        lanes: receivers.clone(),
+        tickets: ticket_receiver.clone(),
+        received: Cell::new(0),
+        affinity_lane,
+      })
+      .collect(),
+  )
 }
 
 struct TokenizerActor {
-  receiver: Receiver<TokenizerMessage>,
+  receiver: LaneReceiver,
   encodings: Arc<Encodings>,
+  custom_encoding: Option<Arc<tiktoken::Encoding>>,
+  // the split pattern of the custom encoding, see `split_regex`
+  custom_split_regex: OnceCell<fancy_regex::Regex>,
+}
+
+/// A built-in encoding that is only loaded the first time it is used.
+struct LazyEncoding {
+  factory: fn() -> Result<tiktoken::Encoding, EncodingFactoryError>,
+  // the encoding and how long it took to load, in milliseconds
+  loaded: OnceCell<(tiktoken::Encoding, f64)>,
+  max_token_byte_length: OnceCell<u32>,
+  split_regex: OnceCell<fancy_regex::Regex>,
+}
+
+impl LazyEncoding {
+  fn new(factory: fn() -> Result<tiktoken::Encoding, EncodingFactoryError>) -> Self {
+    Self {
+      factory,
+      loaded: OnceCell::new(),
+      max_token_byte_length: OnceCell::new(),
+      split_regex: OnceCell::new(),
+    }
+  }
+
+  /// Loads the encoding on first use. A failed load is retried by the next call.
+  fn load(&self) -> Result<&(tiktoken::Encoding, f64), EncodingFactoryError> {
+    self.loaded.get_or_try_init(|| load_timed(self.factory))
+  }
+
+  /// Loads the encoding with `extra_special_tokens` added to its special tokens. Once loaded, the
+  /// encoding is shared by every actor and can't change anymore, so this fails if it has been used
+  /// before, as well as when a string or id is already taken.
+  fn load_with_special_tokens(
+    &self,
+    name: &str,
+    extra_special_tokens: HashMap<String, usize>,
+  ) -> Result<(), Error> {
+    let already_loaded = || {
+      TokenizerErrorKind::InvalidArgument
+        .error(format!("{} is already in use, add special tokens before its first use", name))
+    };
+    if self.loaded.get().is_some() {
+      return Err(already_loaded());
+    }
+    let (base, load_ms) = load_timed(self.factory).map_err(|e| {
+      TokenizerErrorKind::EncodingError.error(format!("Failed to load {}: {}", name, e))
+    })?;
+
+    let mut taken_ids: FxHashSet<usize> =
+      base.mergeable_ranks.values().chain(base.special_tokens.values()).copied().collect();
+    for (token, &id) in &extra_special_tokens {
+      if base.special_tokens.contains_key(token) || !taken_ids.insert(id) {
+        return Err(TokenizerErrorKind::InvalidArgument.error(format!(
+          "Special token {:?} with id {} collides with the {} vocabulary",
+          token, id, name
+        )));
+      }
+    }
+
+    let mut special_tokens = base.special_tokens;
+    special_tokens.extend(extra_special_tokens);
+    let extended =
+      tiktoken::Encoding::new(name, &base.pat_str, base.mergeable_ranks, special_tokens, None)
+        .map_err(|e| TokenizerErrorKind::EncodingError.error(e))?;
+    self.loaded.set((extended, load_ms)).map_err(|_| already_loaded())
+  }
 }
 
 struct Encodings {
-  cl100k_encoding: tiktoken::Encoding,
-  llama3_encoding: tiktoken::Encoding,
-  o200k_encoding: tiktoken::Encoding,
-  codestral_encoding: tiktoken::Encoding,
+  cl100k_encoding: LazyEncoding,
+  llama3_encoding: LazyEncoding,
+  o200k_encoding: LazyEncoding,
+  codestral_encoding: LazyEncoding,
+  r50k_encoding: LazyEncoding,
+  p50k_encoding: LazyEncoding,
+  gpt2_encoding: LazyEncoding,
+}
+
+impl Encodings {
+  fn new() -> Self {
+    Encodings {
+      cl100k_encoding: LazyEncoding::new(tiktoken::EncodingFactory::cl100k_im),
+      llama3_encoding: LazyEncoding::new(tiktoken::EncodingFactory::llama3),
+      o200k_encoding: LazyEncoding::new(tiktoken::EncodingFactory::o200k_im),
+      codestral_encoding: LazyEncoding::new(tiktoken::EncodingFactory::codestral),
+      r50k_encoding: LazyEncoding::new(tiktoken::EncodingFactory::r50k_base),
+      p50k_encoding: LazyEncoding::new(tiktoken::EncodingFactory::p50k_base),
+      gpt2_encoding: LazyEncoding::new(tiktoken::EncodingFactory::gpt2),
+    }
+  }
+
+  fn lazy(&self, encoding: SupportedEncoding) -> Option<&LazyEncoding> {
+    match encoding {
+      SupportedEncoding::Cl100k => Some(&self.cl100k_encoding),
+      SupportedEncoding::Llama3 => Some(&self.llama3_encoding),
+      SupportedEncoding::O200k => Some(&self.o200k_encoding),
+      SupportedEncoding::Codestral => Some(&self.codestral_encoding),
+      SupportedEncoding::R50k => Some(&self.r50k_encoding),
+      SupportedEncoding::P50k => Some(&self.p50k_encoding),
+      SupportedEncoding::Gpt2 => Some(&self.gpt2_encoding),
+      SupportedEncoding::Custom => None,
+    }
+  }
+
+  /// Loads the encoding on first use. Returns None for `SupportedEncoding::Custom`, which lives
+  /// with the tokenizer that loaded it.
+  fn get(
+    &self,
+    encoding: SupportedEncoding,
+  ) -> Result<Option<&tiktoken::Encoding>, EncodingFactoryError> {
+    self.lazy(encoding).map(|lazy| lazy.load().map(|(encoding, _)| encoding)).transpose()
+  }
+
+  /// Loads every built-in encoding that isn't loaded yet.
+  fn load_timings(&self) -> Result<LoadTimings, EncodingFactoryError> {
+    Ok(LoadTimings {
+      cl100k_ms: self.cl100k_encoding.load()?.1,
+      llama3_ms: self.llama3_encoding.load()?.1,
+      o200k_ms: self.o200k_encoding.load()?.1,
+      codestral_ms: self.codestral_encoding.load()?.1,
+      r50k_ms: self.r50k_encoding.load()?.1,
+      p50k_ms: self.p50k_encoding.load()?.1,
+      gpt2_ms: self.gpt2_encoding.load()?.1,
+    })
+  }
+}
+
+/// The special token strings of the encoding, sorted.
+fn special_tokens(encoding: &tiktoken::Encoding) -> Vec<String> {
+  let mut special_tokens: Vec<String> = encoding.special_tokens.keys().cloned().collect();
+  special_tokens.sort();
+  special_tokens
+}
+
+/// Fails with the override keys that are not special tokens of the encoding, unless
+/// `ignore_unknown` is set. Such overrides have no effect, which is easy to miss when an override
+/// map meant for one encoding is reused with another.
+fn check_special_token_overrides(
+  encoding: &tiktoken::Encoding,
+  overrides: &HashMap<String, SpecialTokenAction>,
+  ignore_unknown: bool,
+) -> Result<(), Error> {
+  if ignore_unknown {
+    return Ok(());
+  }
+  let mut unknown: Vec<&str> = overrides
+    .keys()
+    .filter(|token| !encoding.special_tokens.contains_key(*token))
+    .map(String::as_str)
+    .collect();
+  if unknown.is_empty() {
+    return Ok(());
+  }
+  unknown.sort_unstable();
+  Err(
+    TokenizerErrorKind::InvalidArgument
+      .error(format!("Unknown special tokens in special_token_overrides: {}", unknown.join(", "))),
+  )
+}
+
+/// The length in bytes of the encoding's longest token, including the special tokens.
+fn max_token_byte_length(encoding: &tiktoken::Encoding) -> u32 {
+  let token_lengths = encoding.mergeable_ranks.keys().map(Vec::len);
+  let special_token_lengths = encoding.special_tokens.keys().map(String::len);
+  token_lengths.chain(special_token_lengths).max().unwrap_or(0) as u32
+}
+
+/// The number of tokens in the encoding, including the special tokens.
+fn vocab_size(encoding: &tiktoken::Encoding) -> u32 {
+  (encoding.mergeable_ranks.len() + encoding.special_tokens.len()) as u32
 }
 
 enum TokenizerMessage {
@@ -52,12 +541,15 @@ enum TokenizerMessage {
     text: String,
     encoding: SupportedEncoding,
     special_token_handling: tiktoken::SpecialTokenHandling,
+    strip_control_chars: bool,
   },
   EncodeTokens {
     respond_to: oneshot::Sender<anyhow::Result<Vec<u32>>>,
     text: String,
     encoding: SupportedEncoding,
     special_token_handling: tiktoken::SpecialTokenHandling,
+    strip_control_chars: bool,
+    max_special_tokens: Option<u32>,
   },
   // always encodes all special tokens!
   EncodeSingleToken {
@@ -81,49 +573,519 @@ enum TokenizerMessage {
     encoding: SupportedEncoding,
     replace_spaces_with_lower_one_eighth_block: bool,
   },
+  // encodes with every loaded encoding, treating special tokens as normal text
+  EncodeAll {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<(SupportedEncoding, Vec<u32>)>>>,
+    text: String,
+  },
+  CountDiffChanges {
+    respond_to: oneshot::Sender<anyhow::Result<i32>>,
+    unified_diff: String,
+    encoding: SupportedEncoding,
+  },
+  EncodeToCsv {
+    respond_to: oneshot::Sender<anyhow::Result<String>>,
+    text: String,
+    encoding: SupportedEncoding,
+  },
+  DecodeFromCsv {
+    respond_to: oneshot::Sender<anyhow::Result<String>>,
+    csv: String,
+    encoding: SupportedEncoding,
+  },
+  NumTokensBase64 {
+    respond_to: oneshot::Sender<anyhow::Result<i32>>,
+    bytes: Vec<u8>,
+    encoding: SupportedEncoding,
+  },
+  DecodeByteLen {
+    respond_to: oneshot::Sender<anyhow::Result<u32>>,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  },
+  TokensPerChar {
+    respond_to: oneshot::Sender<anyhow::Result<f64>>,
+    text: String,
+    encoding: SupportedEncoding,
+  },
+  SplitTokensBySentence {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<Chunk>>>,
+    text: String,
+    encoding: SupportedEncoding,
+    max_tokens: u32,
+  },
+  UniqueTokens {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<u32>>>,
+    text: String,
+    encoding: SupportedEncoding,
+  },
+  IsLossless {
+    respond_to: oneshot::Sender<anyhow::Result<bool>>,
+    text: String,
+    encoding: SupportedEncoding,
+  },
+  EncodeBatch {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<Vec<u32>>>>,
+    texts: Vec<String>,
+    encoding: SupportedEncoding,
+    special_token_handling: tiktoken::SpecialTokenHandling,
+  },
+  ExactNumTokensBatch {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<i32>>>,
+    texts: Vec<String>,
+    encoding: SupportedEncoding,
+    special_token_handling: tiktoken::SpecialTokenHandling,
+  },
+  NumTokensForChat {
+    respond_to: oneshot::Sender<anyhow::Result<i32>>,
+    messages: Vec<ChatMessage>,
+    encoding: SupportedEncoding,
+    tokens_per_message: i32,
+    tokens_per_name: i32,
+    reply_priming: i32,
+  },
+  TruncateToTokens {
+    respond_to: oneshot::Sender<anyhow::Result<String>>,
+    text: String,
+    max_tokens: u32,
+    encoding: SupportedEncoding,
+    from_end: bool,
+  },
+  EncodeWithOffsets {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<TokenOffset>>>,
+    text: String,
+    encoding: SupportedEncoding,
+    // offsets in UTF-16 code units instead of bytes
+    utf16: bool,
+  },
+  ChunkByTokens {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<String>>>,
+    text: String,
+    chunk_size: u32,
+    overlap: u32,
+    encoding: SupportedEncoding,
+  },
+  DecodeLossy {
+    respond_to: oneshot::Sender<anyhow::Result<String>>,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+    replacement: Option<String>,
+  },
+  DecodeBatch {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<String>>>,
+    sequences: Vec<Vec<u32>>,
+    encoding: SupportedEncoding,
+  },
+  EncodeCancellable {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<u32>>>,
+    text: String,
+    encoding: SupportedEncoding,
+    cancelled: Arc<AtomicBool>,
+  },
+  // encodes chunk by chunk and stops as soon as the count reaches the limit
+  NumTokensAtLeast {
+    respond_to: oneshot::Sender<anyhow::Result<bool>>,
+    text: String,
+    encoding: SupportedEncoding,
+    limit: u32,
+  },
+  // hands the tokens of every chunk to the callback as soon as they are encoded
+  EncodeStreaming {
+    respond_to: oneshot::Sender<anyhow::Result<()>>,
+    text: String,
+    encoding: SupportedEncoding,
+    callback: ThreadsafeFunction<(Vec<u32>, bool), ErrorStrategy::CalleeHandled>,
+  },
+  DecodeBytes {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  },
+  // fails on the first token that is out of range instead of decoding it
+  DecodeChecked {
+    respond_to: oneshot::Sender<anyhow::Result<String>>,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  },
+  Pretokenize {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<String>>>,
+    text: String,
+    encoding: SupportedEncoding,
+  },
+  NumTokensInFile {
+    respond_to: oneshot::Sender<anyhow::Result<i32>>,
+    path: String,
+    encoding: SupportedEncoding,
+  },
+  // counts how often every token occurs across all texts, treating special tokens as normal text
+  TokenHistogram {
+    respond_to: oneshot::Sender<anyhow::Result<HashMap<u32, u32>>>,
+    texts: Vec<String>,
+    encoding: SupportedEncoding,
+  },
+  // the exact count with special tokens as normal text, and the fast estimate
+  CompareCounts {
+    respond_to: oneshot::Sender<anyhow::Result<(usize, usize)>>,
+    text: String,
+    encoding: SupportedEncoding,
+  },
+  EncodeWithPieces {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<(u32, Vec<u8>)>>>,
+    text: String,
+    encoding: SupportedEncoding,
+  },
+  NumTokensAfterRemoving {
+    respond_to: oneshot::Sender<anyhow::Result<i32>>,
+    text: String,
+    remove: Vec<String>,
+    encoding: SupportedEncoding,
+  },
+  // indices of the texts of at most `max_tokens` tokens
+  FilterUnderBudget {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<u32>>>,
+    texts: Vec<String>,
+    max_tokens: u32,
+    encoding: SupportedEncoding,
+  },
+  PackUnderBudget {
+    respond_to: oneshot::Sender<anyhow::Result<PackResult>>,
+    snippets: Vec<String>,
+    max_tokens: u32,
+    separator: String,
+    encoding: SupportedEncoding,
+  },
+  NumTokensCapped {
+    respond_to: oneshot::Sender<anyhow::Result<i32>>,
+    text: String,
+    encoding: SupportedEncoding,
+    cap: u32,
+  },
+  DecodeToPieces {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<String>>>,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  },
+  EncodeSingleTokensBatch {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<i32>>>,
+    byte_strings: Vec<Vec<u8>>,
+    encoding: SupportedEncoding,
+  },
+  NumTokensAll {
+    respond_to: oneshot::Sender<anyhow::Result<Vec<(SupportedEncoding, usize)>>>,
+    text: String,
+  },
+}
+
+impl TokenizerMessage {
+  /// The encoding the message is for, if it is for a single one.
+  fn encoding(&self) -> Option<SupportedEncoding> {
+    match self {
+      TokenizerMessage::ExactNumTokens { encoding, .. }
+      | TokenizerMessage::EncodeTokens { encoding, .. }
+      | TokenizerMessage::EncodeSingleToken { encoding, .. }
+      | TokenizerMessage::DecodeTokens { encoding, .. }
+      | TokenizerMessage::DecodeTokenBytes { encoding, .. }
+      | TokenizerMessage::ApproximateNumTokens { encoding, .. }
+      | TokenizerMessage::CountDiffChanges { encoding, .. }
+      | TokenizerMessage::EncodeToCsv { encoding, .. }
+      | TokenizerMessage::DecodeFromCsv { encoding, .. }
+      | TokenizerMessage::NumTokensBase64 { encoding, .. }
+      | TokenizerMessage::DecodeByteLen { encoding, .. }
+      | TokenizerMessage::TokensPerChar { encoding, .. }
+      | TokenizerMessage::SplitTokensBySentence { encoding, .. }
+      | TokenizerMessage::UniqueTokens { encoding, .. }
+      | TokenizerMessage::IsLossless { encoding, .. }
+      | TokenizerMessage::EncodeBatch { encoding, .. }
+      | TokenizerMessage::ExactNumTokensBatch { encoding, .. }
+      | TokenizerMessage::NumTokensForChat { encoding, .. }
+      | TokenizerMessage::TruncateToTokens { encoding, .. }
+      | TokenizerMessage::EncodeWithOffsets { encoding, .. }
+      | TokenizerMessage::ChunkByTokens { encoding, .. }
+      | TokenizerMessage::DecodeLossy { encoding, .. }
+      | TokenizerMessage::DecodeBatch { encoding, .. }
+      | TokenizerMessage::EncodeCancellable { encoding, .. }
+      | TokenizerMessage::NumTokensAtLeast { encoding, .. }
+      | TokenizerMessage::EncodeStreaming { encoding, .. }
+      | TokenizerMessage::DecodeBytes { encoding, .. }
+      | TokenizerMessage::DecodeChecked { encoding, .. }
+      | TokenizerMessage::Pretokenize { encoding, .. }
+      | TokenizerMessage::NumTokensInFile { encoding, .. }
+      | TokenizerMessage::TokenHistogram { encoding, .. }
+      | TokenizerMessage::CompareCounts { encoding, .. }
+      | TokenizerMessage::EncodeWithPieces { encoding, .. }
+      | TokenizerMessage::NumTokensAfterRemoving { encoding, .. }
+      | TokenizerMessage::FilterUnderBudget { encoding, .. }
+      | TokenizerMessage::PackUnderBudget { encoding, .. }
+      | TokenizerMessage::NumTokensCapped { encoding, .. }
+      | TokenizerMessage::DecodeToPieces { encoding, .. }
+      | TokenizerMessage::EncodeSingleTokensBatch { encoding, .. } => Some(*encoding),
+      TokenizerMessage::EncodeAll { .. } | TokenizerMessage::NumTokensAll { .. } => None,
+    }
+  }
 }
 
 impl TokenizerActor {
-  fn new(receiver: Receiver<TokenizerMessage>, encodings: Arc<Encodings>) -> Self {
-    TokenizerActor { receiver, encodings }
+  fn new(
+    receiver: LaneReceiver,
+    encodings: Arc<Encodings>,
+    custom_encoding: Option<Arc<tiktoken::Encoding>>,
+  ) -> Self {
+    TokenizerActor { receiver, encodings, custom_encoding, custom_split_regex: OnceCell::new() }
   }
 
   fn get_encoding(&self, encoding: SupportedEncoding) -> &tiktoken::Encoding {
-    match encoding {
-      SupportedEncoding::Cl100k => &self.encodings.cl100k_encoding,
-      SupportedEncoding::Llama3 => &self.encodings.llama3_encoding,
-      SupportedEncoding::O200k => &self.encodings.o200k_encoding,
-      SupportedEncoding::Codestral => &self.encodings.codestral_encoding,
+    match (self.encodings.get(encoding), &self.custom_encoding) {
+      (Ok(Some(encoding)), _) => encoding,
+      (Ok(None), Some(custom_encoding)) => custom_encoding,
+      // `Tokenizer::request` loads the encoding before sending and rejects messages for a custom
+      // encoding the tokenizer doesn't have
+      (Err(_), _) | (Ok(None), None) => unreachable!("Encoding was not loaded"),
     }
   }
 
-  fn handle_message(&self, msg: TokenizerMessage) {
-    match msg {
-      TokenizerMessage::ExactNumTokens { respond_to, text, encoding, special_token_handling } => {
-        let tokens = self
-          .get_encoding(encoding)
-          .encode(&text, &special_token_handling)
-          .context("Error encoding string");
+  /// The encoding's split pattern, compiled on first use. Built-in encodings share theirs across
+  /// actors, the custom encoding's is compiled once per actor.
+  fn split_regex(&self, encoding: SupportedEncoding) -> anyhow::Result<&fancy_regex::Regex> {
+    let cell =
+      self.encodings.lazy(encoding).map_or(&self.custom_split_regex, |lazy| &lazy.split_regex);
+    cell.get_or_try_init(|| {
+      fancy_regex::Regex::new(&self.get_encoding(encoding).pat_str).context("Invalid split pattern")
+    })
+  }
 
-        let num_tokens = match tokens {
-          Ok(t) => Ok(t.len() as i32),
-          Err(e) => Err(e),
-        };
+  fn count_normal_text(&self, text: &str, encoding: SupportedEncoding) -> anyhow::Result<usize> {
+    self
+      .get_encoding(encoding)
+      .encode(
+        text,
+        &tiktoken::SpecialTokenHandling {
+          default: tiktoken::SpecialTokenAction::NormalText,
+          ..Default::default()
+        },
+      )
+      .context("Error encoding string")
+      .map(|t| t.len())
+  }
 
-        // The `let _ =` ignores any errors when sending.
-        let _ = respond_to.send(num_tokens);
+  /// Counts the tokens of a UTF-8 file with special tokens treated as normal text, reading it a
+  /// piece at a time. Only text after the last chunk boundary (see `encoding_chunks`) is kept in
+  /// memory, which is all of it for files without such boundaries.
+  fn count_file(&self, path: &str, encoding: SupportedEncoding) -> anyhow::Result<usize> {
+    let mut file = std::fs::File::open(path).map_err(|e| {
+      TokenizerErrorKind::InvalidArgument.actor_error(format!("Failed to open {}: {}", path, e))
+    })?;
+    let mut num_tokens = 0;
+    let mut pending: Vec<u8> = vec![];
+    let mut read_offset = 0;
+    let mut buf = vec![0; FILE_READ_BYTES];
+    loop {
+      let n = file.read(&mut buf).with_context(|| format!("Failed to read {}", path))?;
+      let at_end = n == 0;
+      pending.extend_from_slice(&buf[..n]);
+
+      // a character cut off by the end of this read is completed by the next one
+      let valid_len = match std::str::from_utf8(&pending) {
+        Ok(text) => text.len(),
+        Err(e) if e.error_len().is_none() && !at_end => e.valid_up_to(),
+        Err(e) => {
+          return Err(TokenizerErrorKind::InvalidArgument.actor_error(format!(
+            "{} is not valid UTF-8 at byte {}",
+            path,
+            read_offset + e.valid_up_to()
+          )))
+        }
+      };
+      let text = std::str::from_utf8(&pending[..valid_len])?;
+      if at_end {
+        return Ok(num_tokens + self.count_normal_text(text, encoding)?);
+      }
+
+      let mut chunks = encoding_chunks(text, encoding);
+      // the text after the last boundary may still continue in the next read
+      let rest = chunks.pop().unwrap_or_default();
+      for chunk in chunks {
+        num_tokens += self.count_normal_text(chunk, encoding)?;
+      }
+      let consumed = text.len() - rest.len();
+      read_offset += consumed;
+      pending.drain(..consumed);
+    }
+  }
+
+  fn split_by_sentence(
+    &self,
+    text: &str,
+    encoding: SupportedEncoding,
+    max_tokens: usize,
+  ) -> anyhow::Result<Vec<Chunk>> {
+    if max_tokens == 0 {
+      return Err(TokenizerErrorKind::InvalidArgument.actor_error("max_tokens must be at least 1"));
+    }
+
+    let mut chunks = vec![];
+    let mut current = String::new();
+    let mut current_tokens = 0;
+    for sentence in split_sentences(text) {
+      // each sentence is counted once and a chunk's count is the sum of its sentences, like
+      // `pack_under_budget`, instead of re-encoding the growing chunk for every sentence
+      let sentence_tokens = self.count_normal_text(sentence, encoding)?;
+      if current_tokens + sentence_tokens <= max_tokens {
+        current.push_str(sentence);
+        current_tokens += sentence_tokens;
+        continue;
       }
-      TokenizerMessage::EncodeTokens { respond_to, text, encoding, special_token_handling } => {
+
+      if !current.is_empty() {
+        chunks
+          .push(Chunk { text: std::mem::take(&mut current), num_tokens: current_tokens as u32 });
+      }
+      if sentence_tokens <= max_tokens {
+        current = sentence.to_string();
+        current_tokens = sentence_tokens;
+        continue;
+      }
+
+      // a single sentence over the limit is the only case where we split mid-sentence
+      let tokens = self
+        .get_encoding(encoding)
+        .encode(
+          sentence,
+          &tiktoken::SpecialTokenHandling {
+            default: tiktoken::SpecialTokenAction::NormalText,
+            ..Default::default()
+          },
+        )
+        .context("Error encoding string")?;
+      // the pieces are counted by the tokens they were sliced from; re-encoding a piece on its own
+      // can take more tokens than that and go over the limit
+      for (piece, piece_tokens) in
+        split_tokens_at_char_boundaries(self.get_encoding(encoding), &tokens, max_tokens)?
+      {
+        chunks.push(Chunk { text: piece, num_tokens: piece_tokens as u32 });
+      }
+      current_tokens = 0;
+    }
+    if !current.is_empty() {
+      chunks.push(Chunk { text: current, num_tokens: current_tokens as u32 });
+    }
+
+    Ok(chunks)
+  }
+
+  /// Encodes the text chunk by chunk (see `encoding_chunks`), treating special tokens as normal
+  /// text, and hands the tokens of every chunk to `on_chunk`. Stops early when `on_chunk` returns
+  /// false.
+  fn encode_chunked(
+    &self,
+    text: &str,
+    encoding: SupportedEncoding,
+    mut on_chunk: impl FnMut(Vec<usize>) -> bool,
+  ) -> anyhow::Result<()> {
+    for chunk in encoding_chunks(text, encoding) {
+      let tokens = self
+        .get_encoding(encoding)
+        .encode(
+          chunk,
+          &tiktoken::SpecialTokenHandling {
+            default: tiktoken::SpecialTokenAction::NormalText,
+            ..Default::default()
+          },
+        )
+        .context("Error encoding string")?;
+      if !on_chunk(tokens) {
+        break;
+      }
+    }
+    Ok(())
+  }
+
+  /// Whether the text is more than `limit` tokens long, with special tokens treated as normal
+  /// text. Stops encoding at the first chunk that goes over the limit.
+  fn num_tokens_exceed(
+    &self,
+    text: &str,
+    encoding: SupportedEncoding,
+    limit: usize,
+  ) -> anyhow::Result<bool> {
+    let mut num_tokens = 0;
+    self
+      .encode_chunked(text, encoding, |chunk| {
+        num_tokens += chunk.len();
+        num_tokens <= limit
+      })
+      .map(|()| num_tokens > limit)
+  }
+
+  /// Counts tokens like `num_tokens_exceed`, stopping at the first chunk that reaches `cap`.
+  fn num_tokens_capped(
+    &self,
+    text: &str,
+    encoding: SupportedEncoding,
+    cap: usize,
+  ) -> anyhow::Result<usize> {
+    let mut num_tokens = 0;
+    self
+      .encode_chunked(text, encoding, |chunk| {
+        num_tokens += chunk.len();
+        num_tokens < cap
+      })
+      .map(|()| num_tokens.min(cap))
+  }
+
+  fn handle_message(&self, msg: TokenizerMessage) {
+    match msg {
+      TokenizerMessage::ExactNumTokens {
+        respond_to,
+        text,
+        encoding,
+        special_token_handling,
+        strip_control_chars,
+      } => {
+        let text = if strip_control_chars { strip_c0_control_chars(&text) } else { text };
         let tokens = self
           .get_encoding(encoding)
           .encode(&text, &special_token_handling)
           .context("Error encoding string");
 
-        let tokens = match tokens {
-          Ok(t) => Ok(t.into_iter().map(|t| t as u32).collect()),
+        let num_tokens = match tokens {
+          Ok(t) => Ok(t.len() as i32),
           Err(e) => Err(e),
         };
 
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::EncodeTokens {
+        respond_to,
+        text,
+        encoding,
+        special_token_handling,
+        strip_control_chars,
+        max_special_tokens,
+      } => {
+        let text = if strip_control_chars { strip_c0_control_chars(&text) } else { text };
+        let encoding = self.get_encoding(encoding);
+        let tokens =
+          encoding.encode(&text, &special_token_handling).context("Error encoding string");
+
+        let tokens = match (tokens, max_special_tokens) {
+          (Ok(t), Some(max_special_tokens)) => {
+            let special_tokens: FxHashSet<usize> =
+              encoding.special_tokens.values().copied().collect();
+            let num_special_tokens = t.iter().filter(|t| special_tokens.contains(t)).count();
+            if num_special_tokens > max_special_tokens as usize {
+              Err(TokenizerErrorKind::InvalidArgument.actor_error(format!(
+                "Input contains {} special tokens, but at most {} are allowed",
+                num_special_tokens, max_special_tokens
+              )))
+            } else {
+              Ok(t.into_iter().map(|t| t as u32).collect())
+            }
+          }
+          (Ok(t), None) => Ok(t.into_iter().map(|t| t as u32).collect()),
+          (Err(e), _) => Err(e),
+        };
+
         // The `let _ =` ignores any errors when sending.
         let _ = respond_to.send(tokens);
       }
@@ -132,7 +1094,7 @@ impl TokenizerActor {
 
         let token = match token {
           Ok(t) => Ok(t as u32),
-          Err(_) => Err(anyhow::anyhow!("Token not recognized")),
+          Err(_) => Err(TokenizerErrorKind::UnknownToken.actor_error("Token not recognized")),
         };
 
         // The `let _ =` ignores any errors when sending.
@@ -142,7 +1104,7 @@ impl TokenizerActor {
         let bytes = self.get_encoding(encoding).decode_single_token_bytes(token as usize);
         let bytes = match bytes {
           Ok(b) => Ok(b),
-          Err(e) => Err(anyhow::anyhow!(e)),
+          Err(e) => Err(TokenizerErrorKind::UnknownToken.actor_error(e)),
         };
         let _ = respond_to.send(bytes);
       }
@@ -168,272 +1130,3653 @@ impl TokenizerActor {
         // The `let _ =` ignores any errors when sending.
         let _ = respond_to.send(Ok(tokens as i32));
       }
+      TokenizerMessage::EncodeAll { respond_to, text } => {
+        let special_token_handling = tiktoken::SpecialTokenHandling {
+          default: tiktoken::SpecialTokenAction::NormalText,
+          ..Default::default()
+        };
+        let all_tokens = SupportedEncoding::ALL
+          .into_iter()
+          .map(|encoding| {
+            self
+              .get_encoding(encoding)
+              .encode(&text, &special_token_handling)
+              .with_context(|| format!("Error encoding string with {}", encoding.name()))
+              .map(|t| (encoding, t.into_iter().map(|t| t as u32).collect()))
+          })
+          .collect::<anyhow::Result<Vec<_>>>();
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(all_tokens);
+      }
+      TokenizerMessage::CountDiffChanges { respond_to, unified_diff, encoding } => {
+        let num_tokens = changed_lines_of_unified_diff(&unified_diff).and_then(|changed| {
+          self
+            .get_encoding(encoding)
+            .encode(
+              &changed,
+              &tiktoken::SpecialTokenHandling {
+                default: tiktoken::SpecialTokenAction::NormalText,
+                ..Default::default()
+              },
+            )
+            .context("Error encoding string")
+            .map(|t| t.len() as i32)
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::EncodeToCsv { respond_to, text, encoding } => {
+        let tokens = self
+          .get_encoding(encoding)
+          .encode(
+            &text,
+            &tiktoken::SpecialTokenHandling {
+              default: tiktoken::SpecialTokenAction::NormalText,
+              ..Default::default()
+            },
+          )
+          .context("Error encoding string");
+
+        let csv = match tokens {
+          Ok(t) => Ok(t.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(",")),
+          Err(e) => Err(e),
+        };
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(csv);
+      }
+      TokenizerMessage::DecodeFromCsv { respond_to, csv, encoding } => {
+        let tokens = if csv.trim().is_empty() {
+          Ok(vec![])
+        } else {
+          csv
+            .split(',')
+            .map(|t| t.trim().parse::<usize>().with_context(|| format!("Invalid token id {:?}", t)))
+            .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        let text = match tokens {
+          Ok(t) => Ok(self.get_encoding(encoding).decode(&t)),
+          Err(e) => Err(e),
+        };
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(text);
+      }
+      TokenizerMessage::NumTokensBase64 { respond_to, bytes, encoding } => {
+        let num_tokens = if bytes.is_empty() {
+          Ok(0)
+        } else {
+          let text = base64::engine::general_purpose::STANDARD.encode(&bytes);
+          self
+            .get_encoding(encoding)
+            .encode(
+              &text,
+              &tiktoken::SpecialTokenHandling {
+                default: tiktoken::SpecialTokenAction::NormalText,
+                ..Default::default()
+              },
+            )
+            .context("Error encoding string")
+            .map(|t| t.len() as i32)
+        };
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::DecodeByteLen { respond_to, tokens, encoding } => {
+        let encoding = self.get_encoding(encoding);
+        let byte_len = tokens.into_iter().try_fold(0u32, |len, token| {
+          match encoding.decode_single_token_bytes(token as usize) {
+            Ok(b) => Ok(len + b.len() as u32),
+            Err(e) => Err(TokenizerErrorKind::UnknownToken.actor_error(e)),
+          }
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(byte_len);
+      }
+      TokenizerMessage::TokensPerChar { respond_to, text, encoding } => {
+        let tokens = self
+          .get_encoding(encoding)
+          .encode(
+            &text,
+            &tiktoken::SpecialTokenHandling {
+              default: tiktoken::SpecialTokenAction::NormalText,
+              ..Default::default()
+            },
+          )
+          .context("Error encoding string");
+
+        let ratio = match tokens {
+          Ok(t) => match text.chars().count() {
+            0 => Ok(0.0),
+            num_chars => Ok(t.len() as f64 / num_chars as f64),
+          },
+          Err(e) => Err(e),
+        };
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(ratio);
+      }
+      TokenizerMessage::SplitTokensBySentence { respond_to, text, encoding, max_tokens } => {
+        let chunks = self.split_by_sentence(&text, encoding, max_tokens as usize);
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(chunks);
+      }
+      TokenizerMessage::UniqueTokens { respond_to, text, encoding } => {
+        let tokens = self
+          .get_encoding(encoding)
+          .encode(
+            &text,
+            &tiktoken::SpecialTokenHandling {
+              default: tiktoken::SpecialTokenAction::NormalText,
+              ..Default::default()
+            },
+          )
+          .context("Error encoding string");
+
+        let unique_tokens = match tokens {
+          Ok(mut t) => {
+            t.sort_unstable();
+            t.dedup();
+            Ok(t.into_iter().map(|t| t as u32).collect())
+          }
+          Err(e) => Err(e),
+        };
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(unique_tokens);
+      }
+      TokenizerMessage::IsLossless { respond_to, text, encoding } => {
+        let encoding = self.get_encoding(encoding);
+        let lossless = encoding
+          .encode(
+            &text,
+            &tiktoken::SpecialTokenHandling {
+              default: tiktoken::SpecialTokenAction::NormalText,
+              ..Default::default()
+            },
+          )
+          .context("Error encoding string")
+          .and_then(|t| token_bytes(encoding, &t))
+          .map(|pieces| pieces.concat() == text.as_bytes());
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(lossless);
+      }
+      TokenizerMessage::EncodeBatch { respond_to, texts, encoding, special_token_handling } => {
+        let encoding = self.get_encoding(encoding);
+        let tokens = texts
+          .iter()
+          .enumerate()
+          .map(|(i, text)| {
+            encoding
+              .encode(text, &special_token_handling)
+              .with_context(|| format!("Error encoding string at index {}", i))
+              .map(|t| t.into_iter().map(|t| t as u32).collect())
+          })
+          .collect::<anyhow::Result<Vec<_>>>();
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(tokens);
+      }
+      TokenizerMessage::ExactNumTokensBatch {
+        respond_to,
+        texts,
+        encoding,
+        special_token_handling,
+      } => {
+        let encoding = self.get_encoding(encoding);
+        let num_tokens = texts
+          .iter()
+          .enumerate()
+          .map(|(i, text)| {
+            if text.is_empty() {
+              return Ok(0);
+            }
+            encoding
+              .encode(text, &special_token_handling)
+              .with_context(|| format!("Error encoding string at index {}", i))
+              .map(|t| t.len() as i32)
+          })
+          .collect::<anyhow::Result<Vec<_>>>();
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::NumTokensForChat {
+        respond_to,
+        messages,
+        encoding,
+        tokens_per_message,
+        tokens_per_name,
+        reply_priming,
+      } => {
+        let num_tokens = messages.iter().try_fold(reply_priming, |num_tokens, message| {
+          let mut num_tokens = num_tokens
+            + tokens_per_message
+            + self.count_normal_text(&message.role, encoding)? as i32
+            + self.count_normal_text(&message.content, encoding)? as i32;
+          if let Some(name) = &message.name {
+            num_tokens += tokens_per_name + self.count_normal_text(name, encoding)? as i32;
+          }
+          Ok(num_tokens)
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::TruncateToTokens { respond_to, text, max_tokens, encoding, from_end } => {
+        let truncated =
+          truncate_to_tokens(self.get_encoding(encoding), &text, max_tokens as usize, from_end);
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(truncated);
+      }
+      TokenizerMessage::EncodeWithOffsets { respond_to, text, encoding, utf16 } => {
+        let encoding = self.get_encoding(encoding);
+        let offsets = encoding
+          .encode(
+            &text,
+            &tiktoken::SpecialTokenHandling {
+              default: tiktoken::SpecialTokenAction::NormalText,
+              ..Default::default()
+            },
+          )
+          .context("Error encoding string")
+          .and_then(|tokens| {
+            let mut ranges = token_byte_ranges(encoding, &text, &tokens)?;
+            if utf16 {
+              let starts = utf16_offsets(&text, ranges.iter().map(|&(start, _)| start));
+              let ends = utf16_offsets(&text, ranges.iter().map(|&(_, end)| end));
+              ranges = starts.into_iter().zip(ends).collect();
+            }
+            Ok(
+              tokens
+                .into_iter()
+                .zip(ranges)
+                .map(|(token, (start, end))| TokenOffset {
+                  token: token as u32,
+                  start: start as u32,
+                  end: end as u32,
+                })
+                .collect(),
+            )
+          });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(offsets);
+      }
+      TokenizerMessage::ChunkByTokens { respond_to, text, chunk_size, overlap, encoding } => {
+        let chunks = chunk_by_tokens(
+          self.get_encoding(encoding),
+          &text,
+          chunk_size as usize,
+          overlap as usize,
+        );
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(chunks);
+      }
+      TokenizerMessage::DecodeLossy { respond_to, tokens, encoding, replacement } => {
+        let tokens = tokens.into_iter().map(|t| t as usize).collect::<Vec<_>>();
+        let text = token_bytes(self.get_encoding(encoding), &tokens).map(|pieces| {
+          let bytes = pieces.concat();
+          match replacement {
+            Some(replacement) => utf8_lossy_with(&bytes, &replacement),
+            None => String::from_utf8_lossy(&bytes).into_owned(),
+          }
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(text);
+      }
+      TokenizerMessage::DecodeBatch { respond_to, sequences, encoding } => {
+        let encoding = self.get_encoding(encoding);
+        let texts = sequences
+          .into_iter()
+          .map(|tokens| {
+            encoding.decode(&tokens.into_iter().map(|t| t as usize).collect::<Vec<_>>())
+          })
+          .collect();
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(Ok(texts));
+      }
+      TokenizerMessage::EncodeCancellable { respond_to, text, encoding, cancelled } => {
+        let mut tokens = vec![];
+        let result = self.encode_chunked(&text, encoding, |chunk| {
+          tokens.extend(chunk.into_iter().map(|t| t as u32));
+          !cancelled.load(Ordering::Relaxed)
+        });
+
+        let tokens = match result {
+          Ok(()) if cancelled.load(Ordering::Relaxed) => {
+            Err(TokenizerErrorKind::Cancelled.actor_error("Encoding was cancelled"))
+          }
+          Ok(()) => Ok(tokens),
+          Err(e) => Err(e),
+        };
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(tokens);
+      }
+      TokenizerMessage::NumTokensAtLeast { respond_to, text, encoding, limit } => {
+        let limit = limit as usize;
+        let result = self.num_tokens_capped(&text, encoding, limit).map(|n| n >= limit);
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(result);
+      }
+      TokenizerMessage::EncodeStreaming { respond_to, text, encoding, callback } => {
+        let result = self.encode_chunked(&text, encoding, |chunk| {
+          let tokens = chunk.into_iter().map(|t| t as u32).collect();
+          callback.call(Ok((tokens, false)), ThreadsafeFunctionCallMode::NonBlocking);
+          true
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(result);
+      }
+      TokenizerMessage::DecodeBytes { respond_to, tokens, encoding } => {
+        let tokens = tokens.into_iter().map(|t| t as usize).collect::<Vec<_>>();
+        let bytes = token_bytes(self.get_encoding(encoding), &tokens).map(|pieces| pieces.concat());
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(bytes);
+      }
+      TokenizerMessage::DecodeChecked { respond_to, tokens, encoding } => {
+        let encoding = self.get_encoding(encoding);
+        // the id ranges have gaps, so every token has to be looked up
+        let unknown =
+          tokens.iter().position(|&t| encoding.decode_single_token_bytes(t as usize).is_err());
+        let text = match unknown {
+          Some(i) => Err(
+            TokenizerErrorKind::UnknownToken
+              .actor_error(format!("Token {} at index {} is not in the encoding", tokens[i], i)),
+          ),
+          None => Ok(encoding.decode(&tokens.into_iter().map(|t| t as usize).collect::<Vec<_>>())),
+        };
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(text);
+      }
+      TokenizerMessage::Pretokenize { respond_to, text, encoding } => {
+        let pieces = self.split_regex(encoding).and_then(|regex| pretokenize(regex, &text));
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(pieces);
+      }
+      TokenizerMessage::NumTokensInFile { respond_to, path, encoding } => {
+        let num_tokens = self.count_file(&path, encoding).map(|n| n as i32);
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::TokenHistogram { respond_to, texts, encoding } => {
+        let encoding = self.get_encoding(encoding);
+        let mut histogram = HashMap::new();
+        let result = texts
+          .iter()
+          .enumerate()
+          .try_for_each(|(i, text)| {
+            let tokens = encoding
+              .encode(
+                text,
+                &tiktoken::SpecialTokenHandling {
+                  default: tiktoken::SpecialTokenAction::NormalText,
+                  ..Default::default()
+                },
+              )
+              .with_context(|| format!("Error encoding string at index {}", i))?;
+            for token in tokens {
+              *histogram.entry(token as u32).or_insert(0) += 1;
+            }
+            Ok(())
+          })
+          .map(|()| histogram);
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(result);
+      }
+      TokenizerMessage::CompareCounts { respond_to, text, encoding } => {
+        let counts = self.count_normal_text(&text, encoding).map(|exact| {
+          let approx =
+            self.get_encoding(encoding).estimate_num_tokens_no_special_tokens_fast(&text, false);
+          (exact, approx)
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(counts);
+      }
+      TokenizerMessage::EncodeWithPieces { respond_to, text, encoding } => {
+        let encoding = self.get_encoding(encoding);
+        let pieces = encoding
+          .encode(
+            &text,
+            &tiktoken::SpecialTokenHandling {
+              default: tiktoken::SpecialTokenAction::NormalText,
+              ..Default::default()
+            },
+          )
+          .context("Error encoding string")
+          .and_then(|tokens| {
+            let bytes = token_bytes(encoding, &tokens)?;
+            Ok(tokens.into_iter().map(|t| t as u32).zip(bytes).collect())
+          });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(pieces);
+      }
+      TokenizerMessage::NumTokensAfterRemoving { respond_to, text, remove, encoding } => {
+        let num_tokens =
+          self.count_normal_text(&remove_all(text, remove), encoding).map(|n| n as i32);
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::FilterUnderBudget { respond_to, texts, max_tokens, encoding } => {
+        let indices = texts
+          .iter()
+          .enumerate()
+          .filter_map(|(i, text)| {
+            match self.num_tokens_exceed(text, encoding, max_tokens as usize) {
+              Ok(true) => None,
+              Ok(false) => Some(Ok(i as u32)),
+              Err(e) => Some(Err(e.context(format!("Error encoding string at index {}", i)))),
+            }
+          })
+          .collect::<anyhow::Result<Vec<_>>>();
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(indices);
+      }
+      TokenizerMessage::PackUnderBudget {
+        respond_to,
+        snippets,
+        max_tokens,
+        separator,
+        encoding,
+      } => {
+        let packed = self.count_normal_text(&separator, encoding).and_then(|separator_tokens| {
+          pack_greedily(
+            snippets.iter().map(|snippet| self.count_normal_text(snippet, encoding)),
+            separator_tokens,
+            max_tokens as usize,
+          )
+        });
+        let packed = packed.map(|(taken, total_tokens)| PackResult {
+          included_indices: (0..taken as u32).collect(),
+          total_tokens: total_tokens as i32,
+          text: snippets[..taken].join(&separator),
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(packed);
+      }
+      TokenizerMessage::NumTokensCapped { respond_to, text, encoding, cap } => {
+        let num_tokens = self.num_tokens_capped(&text, encoding, cap as usize).map(|n| n as i32);
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(num_tokens);
+      }
+      TokenizerMessage::DecodeToPieces { respond_to, tokens, encoding } => {
+        let tokens = tokens.into_iter().map(|t| t as usize).collect::<Vec<_>>();
+        let pieces = token_bytes(self.get_encoding(encoding), &tokens).map(|pieces| {
+          pieces.iter().map(|bytes| String::from_utf8_lossy(bytes).into_owned()).collect()
+        });
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(pieces);
+      }
+      TokenizerMessage::EncodeSingleTokensBatch { respond_to, byte_strings, encoding } => {
+        let encoding = self.get_encoding(encoding);
+        let tokens = byte_strings
+          .iter()
+          .map(|bytes| encoding.encode_single_token_bytes(bytes).map_or(-1, |t| t as i32))
+          .collect();
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(Ok(tokens));
+      }
+      TokenizerMessage::NumTokensAll { respond_to, text } => {
+        let counts = SupportedEncoding::ALL
+          .into_iter()
+          .map(|encoding| {
+            self
+              .count_normal_text(&text, encoding)
+              .with_context(|| format!("Error encoding string with {}", encoding.name()))
+              .map(|num_tokens| (encoding, num_tokens))
+          })
+          .collect::<anyhow::Result<Vec<_>>>();
+
+        // The `let _ =` ignores any errors when sending.
+        let _ = respond_to.send(counts);
+      }
     }
   }
 }
 
-fn run_tokenizer_actor(actor: TokenizerActor) {
-  while let Ok(msg) = actor.receiver.recv_blocking() {
-    actor.handle_message(msg);
+/// Removes the C0 control characters U+0000 through U+001F, except for tab (U+0009) and line
+/// feed (U+000A). Note that carriage return (U+000D) is stripped too. DEL (U+007F) and C1 controls
+/// are left alone.
+fn strip_c0_control_chars(text: &str) -> String {
+  text.chars().filter(|&c| c >= '\u{20}' || c == '\t' || c == '\n').collect()
+}
+
+/// Collects the added and removed lines of a unified diff (without their `+`/`-` markers), each
+/// terminated by a newline. File headers, hunk headers and context lines are skipped. An empty diff
+/// has no changes, but any other text without a hunk header is not a diff.
+fn changed_lines_of_unified_diff(diff: &str) -> anyhow::Result<String> {
+  fn parse_range(range: &str) -> Option<u32> {
+    match range.split_once(',') {
+      Some((start, len)) => start.parse::<u32>().ok().and(len.parse().ok()),
+      None => range.parse::<u32>().ok().map(|_| 1),
+    }
+  }
+
+  let mut changed = String::new();
+  let mut old_remaining = 0u32;
+  let mut new_remaining = 0u32;
+  let mut has_hunk = false;
+  for (i, line) in diff.lines().enumerate() {
+    let line_number = i + 1;
+    if old_remaining == 0 && new_remaining == 0 {
+      // outside of a hunk everything but a hunk header is a file header we don't care about
+      if line.starts_with("@@") {
+        let (old_range, new_range) = line
+          .strip_prefix("@@ -")
+          .and_then(|header| header.split_once(" @@"))
+          .and_then(|(ranges, _)| ranges.split_once(" +"))
+          .with_context(|| format!("Malformed hunk header on line {}", line_number))?;
+        match (parse_range(old_range), parse_range(new_range)) {
+          (Some(old_len), Some(new_len)) => {
+            old_remaining = old_len;
+            new_remaining = new_len;
+            has_hunk = true;
+          }
+          _ => anyhow::bail!("Malformed hunk header on line {}", line_number),
+        }
+      }
+      continue;
+    }
+
+    let (counts_old, counts_new) = match line.chars().next() {
+      Some('+') => (false, true),
+      Some('-') => (true, false),
+      // some tools strip the trailing space from empty context lines
+      Some(' ') | None => (true, true),
+      Some('\\') => continue,
+      _ => anyhow::bail!("Unexpected line {} inside hunk", line_number),
+    };
+    if (counts_old && old_remaining == 0) || (counts_new && new_remaining == 0) {
+      anyhow::bail!("Line {} does not fit in its hunk", line_number);
+    }
+    if counts_old {
+      old_remaining -= 1;
+    }
+    if counts_new {
+      new_remaining -= 1;
+    }
+    if counts_old != counts_new {
+      changed.push_str(&line[1..]);
+      changed.push('\n');
+    }
+  }
+
+  if old_remaining != 0 || new_remaining != 0 {
+    anyhow::bail!("Diff ends in the middle of a hunk");
+  }
+  if !has_hunk && !diff.trim().is_empty() {
+    anyhow::bail!("Diff has no hunk header");
   }
+  Ok(changed)
 }
 
-#[napi]
-#[derive(Clone)]
-pub struct Tokenizer {
-  sender: Sender<TokenizerMessage>,
+/// Splits the text after every run of `.`, `!` or `?` that is followed by whitespace. The whitespace
+/// stays at the start of the next sentence, which is also where the encodings' split patterns put it.
+fn split_sentences(text: &str) -> Vec<&str> {
+  let mut sentences = vec![];
+  let mut start = 0;
+  let mut chars = text.char_indices().peekable();
+  while let Some((i, c)) = chars.next() {
+    if matches!(c, '.' | '!' | '?') && chars.peek().is_some_and(|(_, next)| next.is_whitespace()) {
+      let end = i + c.len_utf8();
+      sentences.push(&text[start..end]);
+      start = end;
+    }
+  }
+  if start < text.len() {
+    sentences.push(&text[start..]);
+  }
+  sentences
 }
 
-#[napi]
-pub enum SpecialTokenAction {
-  /// The special token is forbidden. If it is included in the string, an error will be returned.
-  Forbidden = 0,
-  /// The special token is tokenized as normal text.
-  NormalText = 1,
-  /// The special token is treated as the special token it is. If this is applied to a specific text and the text is NOT a special token then an error will be returned. If it is the default action no error will be returned, don't worry.
-  Special = 2,
+/// Returns the bytes each of the tokens decodes to.
+fn token_bytes(encoding: &tiktoken::Encoding, tokens: &[usize]) -> anyhow::Result<Vec<Vec<u8>>> {
+  tokens
+    .iter()
+    .map(|&token| {
+      encoding
+        .decode_single_token_bytes(token)
+        .map_err(|e| TokenizerErrorKind::UnknownToken.actor_error(e))
+    })
+    .collect()
 }
 
-impl SpecialTokenAction {
-  pub fn to_tiktoken(&self) -> tiktoken::SpecialTokenAction {
-    match self {
-      SpecialTokenAction::Forbidden => tiktoken::SpecialTokenAction::Forbidden,
-      SpecialTokenAction::NormalText => tiktoken::SpecialTokenAction::NormalText,
-      SpecialTokenAction::Special => tiktoken::SpecialTokenAction::Special,
+/// Splits the tokens into pieces of at most `max_tokens` tokens each and decodes them, along with
+/// the number of tokens in each piece. Every piece ends at the last token boundary that is also a
+/// character boundary, so no piece contains a replacement character; a piece only goes over
+/// `max_tokens` if a single character spans more tokens than that.
+fn split_tokens_at_char_boundaries(
+  encoding: &tiktoken::Encoding,
+  tokens: &[usize],
+  max_tokens: usize,
+) -> anyhow::Result<Vec<(String, usize)>> {
+  let pieces = token_bytes(encoding, tokens)?;
+  let mut split = vec![];
+  let mut start = 0;
+  while start < pieces.len() {
+    let mut end = (start + max_tokens).min(pieces.len());
+    while end > start + 1 && std::str::from_utf8(&pieces[start..end].concat()).is_err() {
+      end -= 1;
     }
+    while end < pieces.len() && std::str::from_utf8(&pieces[start..end].concat()).is_err() {
+      end += 1;
+    }
+    split.push((String::from_utf8_lossy(&pieces[start..end].concat()).into_owned(), end - start));
+    start = end;
+  }
+  Ok(split)
+}
+
+/// Truncates the text to its first (or last, if `from_end`) `max_tokens` tokens. The cut is moved
+/// to the nearest character boundary inside the kept tokens, so the result may have fewer tokens
+/// but never contains a split character. Special tokens are treated as normal text.
+fn truncate_to_tokens(
+  encoding: &tiktoken::Encoding,
+  text: &str,
+  max_tokens: usize,
+  from_end: bool,
+) -> anyhow::Result<String> {
+  let tokens = encoding
+    .encode(
+      text,
+      &tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+    )
+    .context("Error encoding string")?;
+  if tokens.len() <= max_tokens {
+    return Ok(text.to_string());
   }
+
+  let kept = if from_end { &tokens[tokens.len() - max_tokens..] } else { &tokens[..max_tokens] };
+  let kept_len: usize = token_bytes(encoding, kept)?.iter().map(|b| b.len()).sum();
+  if from_end {
+    let mut start = text.len() - kept_len;
+    while !text.is_char_boundary(start) {
+      start += 1;
+    }
+    Ok(text[start..].to_string())
+  } else {
+    let mut end = kept_len;
+    while !text.is_char_boundary(end) {
+      end -= 1;
+    }
+    Ok(text[..end].to_string())
+  }
+}
+
+/// Returns the byte range of the text each token covers. Ranges are widened to the enclosing
+/// character boundaries, so tokens that share a multi-byte character have overlapping ranges.
+fn token_byte_ranges(
+  encoding: &tiktoken::Encoding,
+  text: &str,
+  tokens: &[usize],
+) -> anyhow::Result<Vec<(usize, usize)>> {
+  let mut ranges = Vec::with_capacity(tokens.len());
+  let mut offset = 0;
+  for bytes in token_bytes(encoding, tokens)? {
+    let (mut start, mut end) = (offset, offset + bytes.len());
+    offset = end;
+    if end > text.len() {
+      anyhow::bail!("Tokens do not decode to the encoded text");
+    }
+    while !text.is_char_boundary(start) {
+      start -= 1;
+    }
+    while !text.is_char_boundary(end) {
+      end += 1;
+    }
+    ranges.push((start, end));
+  }
+  Ok(ranges)
+}
+
+/// Converts byte offsets into the text, which must be character boundaries in non-decreasing
+/// order, to offsets in UTF-16 code units.
+fn utf16_offsets(text: &str, byte_offsets: impl IntoIterator<Item = usize>) -> Vec<usize> {
+  let mut chars = text.char_indices().peekable();
+  let mut utf16_offset = 0;
+  byte_offsets
+    .into_iter()
+    .map(|byte_offset| {
+      while let Some(&(i, c)) = chars.peek() {
+        if i >= byte_offset {
+          break;
+        }
+        utf16_offset += c.len_utf16();
+        chars.next();
+      }
+      utf16_offset
+    })
+    .collect()
+}
+
+/// Splits the text into windows of `chunk_size` tokens, each starting `chunk_size - overlap` tokens
+/// after the previous one. The chunks are slices of the text, with cuts inside a multi-byte
+/// character widened to include the whole character.
+fn chunk_by_tokens(
+  encoding: &tiktoken::Encoding,
+  text: &str,
+  chunk_size: usize,
+  overlap: usize,
+) -> anyhow::Result<Vec<String>> {
+  if overlap >= chunk_size {
+    return Err(TokenizerErrorKind::InvalidArgument.actor_error(format!(
+      "overlap ({}) must be smaller than chunk_size ({})",
+      overlap, chunk_size
+    )));
+  }
+  let tokens = encoding
+    .encode(
+      text,
+      &tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+    )
+    .context("Error encoding string")?;
+
+  // offsets[i] is the byte offset where token i starts
+  let mut offsets = vec![0];
+  for bytes in token_bytes(encoding, &tokens)? {
+    offsets.push(offsets[offsets.len() - 1] + bytes.len());
+  }
+
+  let mut chunks = vec![];
+  let mut start = 0;
+  while start < tokens.len() {
+    let end = (start + chunk_size).min(tokens.len());
+    let (mut start_byte, mut end_byte) = (offsets[start], offsets[end].min(text.len()));
+    while !text.is_char_boundary(start_byte) {
+      start_byte -= 1;
+    }
+    while !text.is_char_boundary(end_byte) {
+      end_byte += 1;
+    }
+    chunks.push(text[start_byte..end_byte].to_string());
+    if end == tokens.len() {
+      break;
+    }
+    start += chunk_size - overlap;
+  }
+  Ok(chunks)
+}
+
+/// Parses the contents of a .tiktoken rank file, where every line is a base64 encoded token and
+/// its rank separated by a space.
+fn parse_tiktoken_ranks(contents: &str) -> anyhow::Result<HashMap<Vec<u8>, usize>> {
+  let mut ranks = HashMap::new();
+  let mut seen_ranks = FxHashSet::default();
+  for (i, line) in contents.lines().enumerate() {
+    let line_number = i + 1;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let (token, rank) = line
+      .split_once(' ')
+      .with_context(|| format!("Line {}: expected a token and a rank", line_number))?;
+    let token = base64::engine::general_purpose::STANDARD
+      .decode(token)
+      .with_context(|| format!("Line {}: invalid base64 {:?}", line_number, token))?;
+    let rank = rank
+      .trim()
+      .parse::<usize>()
+      .with_context(|| format!("Line {}: invalid rank {:?}", line_number, rank))?;
+    if !seen_ranks.insert(rank) {
+      anyhow::bail!("Line {}: duplicate rank {}", line_number, rank);
+    }
+    if ranks.insert(token, rank).is_some() {
+      anyhow::bail!("Line {}: duplicate token", line_number);
+    }
+  }
+  Ok(ranks)
+}
+
+/// `encode_chunks` with the default chunk size. Custom encodings have an unknown split pattern, so
+/// their text always stays in one chunk.
+fn encoding_chunks(text: &str, encoding: SupportedEncoding) -> Vec<&str> {
+  match encoding {
+    SupportedEncoding::Custom => vec![text],
+    _ => encode_chunks(text, ENCODE_CHUNK_BYTES),
+  }
+}
+
+/// Splits the text into chunks of at least `min_chunk_bytes` (except for the last one) that encode
+/// to the same tokens separately as they do together. The split patterns of all built-in encodings
+/// treat these as boundaries no matter what comes after them:
+/// - after a single newline that follows a non-whitespace character and precedes a letter, which is
+///   preferred within `min_chunk_bytes` past the minimum,
+/// - otherwise before a space that follows a non-whitespace character, as no piece runs from a
+///   non-whitespace character into a space after it, so long lines and minified text get split too.
+///
+/// Text without either boundary stays in one chunk.
+fn encode_chunks(text: &str, min_chunk_bytes: usize) -> Vec<&str> {
+  let mut chunks = vec![];
+  let mut start = 0;
+  while start + min_chunk_bytes < text.len() {
+    let search_from = start + min_chunk_bytes;
+    let window_end = (search_from + min_chunk_bytes).min(text.len());
+    let end = encode_chunk_boundary(text, start, search_from..window_end, false)
+      .or_else(|| encode_chunk_boundary(text, start, search_from..text.len(), true));
+    let Some(end) = end else {
+      break;
+    };
+    chunks.push(&text[start..end]);
+    start = end;
+  }
+  chunks.push(&text[start..]);
+  chunks
+}
+
+/// The first end of a chunk starting at `start` within `range`, see `encode_chunks`. Only looks at
+/// the bytes in `range`, which can start or end inside a multi-byte character.
+fn encode_chunk_boundary(
+  text: &str,
+  start: usize,
+  range: std::ops::Range<usize>,
+  allow_space: bool,
+) -> Option<usize> {
+  let bytes = text.as_bytes();
+  let after_non_whitespace =
+    |i: usize| i > start && text[start..i].chars().next_back().is_some_and(|c| !c.is_whitespace());
+  range.find_map(|i| match bytes[i] {
+    b'\n' => {
+      let end = i + 1;
+      let before_letter = text[end..].chars().next().is_some_and(|c| c.is_alphabetic());
+      (after_non_whitespace(i) && before_letter).then_some(end)
+    }
+    b' ' if allow_space => after_non_whitespace(i).then_some(i),
+    _ => None,
+  })
+}
+
+/// Splits the text with the pretokenization regex, the step before BPE merges the pieces into
+/// tokens. Text the regex doesn't match becomes a piece of its own, so the pieces always
+/// concatenate to the text.
+fn pretokenize(regex: &fancy_regex::Regex, text: &str) -> anyhow::Result<Vec<String>> {
+  let mut pieces = vec![];
+  let mut end = 0;
+  for m in regex.find_iter(text) {
+    let m = m.context("Error applying split pattern")?;
+    if m.start() > end {
+      pieces.push(text[end..m.start()].to_string());
+    }
+    pieces.push(m.as_str().to_string());
+    end = m.end();
+  }
+  if end < text.len() {
+    pieces.push(text[end..].to_string());
+  }
+  Ok(pieces)
+}
+
+/// Aggregates the token counts of a batch, keeping the counts themselves.
+fn batch_stats(counts: Vec<i32>) -> BatchStats {
+  let total = counts.iter().map(|&n| n as i64).sum::<i64>();
+  BatchStats {
+    total,
+    max: counts.iter().copied().max().unwrap_or(0),
+    min: counts.iter().copied().min().unwrap_or(0),
+    mean: if counts.is_empty() { 0.0 } else { total as f64 / counts.len() as f64 },
+    counts,
+  }
+}
+
+/// Splits the text into chunks of about `target_bytes` bytes each. A chunk ends right after the
+/// whitespace nearest to its target end, looking at most `target_bytes` past it, so words stay
+/// whole where possible. The chunks concatenate to the text.
+fn split_near_whitespace(text: &str, target_bytes: usize) -> Vec<&str> {
+  let floor_char_boundary = |mut i: usize| {
+    while !text.is_char_boundary(i) {
+      i -= 1;
+    }
+    i
+  };
+  let target_bytes = target_bytes.max(1);
+  let mut chunks = vec![];
+  let mut start = 0;
+  while text.len() - start > target_bytes {
+    let target = floor_char_boundary(start + target_bytes);
+    let backward = text[start..target]
+      .char_indices()
+      .rev()
+      .find(|(_, c)| c.is_whitespace())
+      .map(|(i, c)| start + i + c.len_utf8());
+    let window_end = floor_char_boundary((target + target_bytes).min(text.len()));
+    let forward = text[target..window_end]
+      .char_indices()
+      .find(|(_, c)| c.is_whitespace())
+      .map(|(i, c)| target + i + c.len_utf8());
+    let end = match (backward, forward) {
+      (Some(backward), Some(forward)) if forward - target < target - backward => forward,
+      (Some(backward), _) => backward,
+      (None, Some(forward)) => forward,
+      // no whitespace nearby, cut mid-word but always make progress
+      (None, None) if target > start => target,
+      (None, None) => start + text[start..].chars().next().map_or(0, char::len_utf8),
+    };
+    chunks.push(&text[start..end]);
+    start = end;
+  }
+  if start < text.len() {
+    chunks.push(&text[start..]);
+  }
+  chunks
+}
+
+/// Replaces every `{{key}}` placeholder in the template with `value(key)`, where the key is
+/// trimmed, so `{{ key }}` works too. A `{{` without a matching `}}` is kept as is. Fails with the
+/// key of the first placeholder without a value.
+fn fill_template<'a>(
+  template: &str,
+  mut value: impl FnMut(&str) -> Option<&'a str>,
+) -> Result<String, String> {
+  let mut filled = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(open) = rest.find("{{") {
+    let Some(close) = rest[open + 2..].find("}}") else {
+      break;
+    };
+    let key = rest[open + 2..open + 2 + close].trim();
+    filled.push_str(&rest[..open]);
+    filled.push_str(value(key).ok_or_else(|| key.to_string())?);
+    rest = &rest[open + 2 + close + 2..];
+  }
+  filled.push_str(rest);
+  Ok(filled)
+}
+
+/// The byte offset just past the last `.`, `!` or `?` in `text[..kept]` that is followed by
+/// whitespace or the end of the text, if there is one. The character after a terminator is looked
+/// up in the whole text, so a sentence that ends exactly at `kept` counts.
+fn last_sentence_end(text: &str, kept: usize) -> Option<usize> {
+  text[..kept]
+    .char_indices()
+    .filter(|&(_, c)| matches!(c, '.' | '!' | '?'))
+    .map(|(i, c)| i + c.len_utf8())
+    .filter(|&end| end == text.len() || text[end..].starts_with(char::is_whitespace))
+    .last()
+}
+
+/// Deletes every occurrence of each of the strings from the text, longest first so that a string
+/// contained in a longer one doesn't break up the longer one's occurrences. Empty strings are
+/// ignored.
+fn remove_all(mut text: String, mut remove: Vec<String>) -> String {
+  remove.sort_by_key(|s| std::cmp::Reverse(s.len()));
+  for s in remove.iter().filter(|s| !s.is_empty()) {
+    text = text.replace(s.as_str(), "");
+  }
+  text
+}
+
+/// Takes the longest prefix of the bytes that is complete UTF-8 off the front and returns it as a
+/// string. Bytes that can never be valid UTF-8 become U+FFFD, while an incomplete character at the
+/// end stays in `pending` until the bytes completing it arrive.
+fn take_complete_utf8(pending: &mut Vec<u8>) -> String {
+  let mut text = String::new();
+  let mut start = 0;
+  loop {
+    match std::str::from_utf8(&pending[start..]) {
+      Ok(valid) => {
+        text.push_str(valid);
+        start = pending.len();
+        break;
+      }
+      Err(e) => {
+        let valid_end = start + e.valid_up_to();
+        text.push_str(&String::from_utf8_lossy(&pending[start..valid_end]));
+        match e.error_len() {
+          Some(invalid_len) => {
+            text.push('\u{FFFD}');
+            start = valid_end + invalid_len;
+          }
+          None => {
+            start = valid_end;
+            break;
+          }
+        }
+      }
+    }
+  }
+  pending.drain(..start);
+  text
+}
+
+/// Takes items from the front as long as their total stays within `max_tokens`, where every item
+/// after the first also costs `separator_tokens`. Stops at the first item that doesn't fit, without
+/// looking at the rest. Returns the number of items taken and their total.
+fn pack_greedily(
+  num_tokens: impl IntoIterator<Item = anyhow::Result<usize>>,
+  separator_tokens: usize,
+  max_tokens: usize,
+) -> anyhow::Result<(usize, usize)> {
+  let (mut taken, mut total) = (0, 0);
+  for item_tokens in num_tokens {
+    let cost = item_tokens? + if taken > 0 { separator_tokens } else { 0 };
+    if total + cost > max_tokens {
+      break;
+    }
+    taken += 1;
+    total += cost;
+  }
+  Ok((taken, total))
+}
+
+/// Like `String::from_utf8_lossy`, but with `replacement` in place of U+FFFD for every invalid
+/// sequence.
+fn utf8_lossy_with(bytes: &[u8], replacement: &str) -> String {
+  let mut text = String::with_capacity(bytes.len());
+  for chunk in bytes.utf8_chunks() {
+    text.push_str(chunk.valid());
+    if !chunk.invalid().is_empty() {
+      text.push_str(replacement);
+    }
+  }
+  text
+}
+
+/// Runs the actor until its lanes are closed. If handling a message panics, the message is
+/// dropped, so its caller gets an `ActorDead` error, `restarts` goes up by one and a fresh actor
+/// takes over the same lanes. The runtime only drives waiting for the next message, messages are
+/// handled on this thread.
+fn run_tokenizer_actor(
+  mut actor: TokenizerActor,
+  runtime: tokio::runtime::Runtime,
+  restarts: Arc<AtomicU32>,
+) {
+  while let Ok(msg) = runtime.block_on(actor.receiver.recv()) {
+    if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| actor.handle_message(msg))) {
+      let reason = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown panic");
+      log::error!("Tokenizer actor panicked, restarting it: {}", reason);
+      restarts.fetch_add(1, Ordering::Relaxed);
+      actor = TokenizerActor::new(
+        actor.receiver.clone(),
+        actor.encodings.clone(),
+        actor.custom_encoding.clone(),
+      );
+    }
+  }
+}
+
+/// An actor thread and the number of times its actor was restarted after a panic.
+struct ActorThread {
+  handle: JoinHandle<()>,
+  restarts: Arc<AtomicU32>,
+}
+
+#[napi]
+#[derive(Clone)]
+pub struct Tokenizer {
+  sender: LaneSender,
+  actors: Arc<Mutex<Vec<ActorThread>>>,
+  encodings: Arc<Encodings>,
+  custom_encoding: Option<Arc<tiktoken::Encoding>>,
+}
+
+#[napi]
+pub enum SpecialTokenAction {
+  /// The special token is forbidden. If it is included in the string, an error will be returned.
+  Forbidden = 0,
+  /// The special token is tokenized as normal text.
+  NormalText = 1,
+  /// The special token is treated as the special token it is. If this is applied to a specific text and the text is NOT a special token then an error will be returned. If it is the default action no error will be returned, don't worry.
+  Special = 2,
+}
+
+impl SpecialTokenAction {
+  pub fn to_tiktoken(&self) -> tiktoken::SpecialTokenAction {
+    match self {
+      SpecialTokenAction::Forbidden => tiktoken::SpecialTokenAction::Forbidden,
+      SpecialTokenAction::NormalText => tiktoken::SpecialTokenAction::NormalText,
+      SpecialTokenAction::Special => tiktoken::SpecialTokenAction::Special,
+    }
+  }
+}
+
+#[napi(object)]
+pub struct Chunk {
+  pub text: String,
+  pub num_tokens: u32,
+}
+
+#[napi(object)]
+pub struct ChatMessage {
+  pub role: String,
+  pub content: String,
+  pub name: Option<String>,
+}
+
+#[napi(object)]
+pub struct TokenOffset {
+  pub token: u32,
+  /// Byte offset into the text where the token starts.
+  pub start: u32,
+  /// Byte offset into the text where the token ends, exclusive.
+  pub end: u32,
+}
+
+#[napi(object)]
+pub struct TokenEstimate {
+  pub estimate: i32,
+  /// The exact count is expected to be at least this.
+  pub lower: i32,
+  /// The exact count is expected to be at most this, but the bound is not measured.
+  pub upper: i32,
+}
+
+/// How long loading each built-in encoding took, in milliseconds.
+#[napi(object)]
+pub struct LoadTimings {
+  pub cl100k_ms: f64,
+  pub llama3_ms: f64,
+  pub o200k_ms: f64,
+  pub codestral_ms: f64,
+  pub r50k_ms: f64,
+  pub p50k_ms: f64,
+  pub gpt2_ms: f64,
+}
+
+/// One part of a multimodal chat message content, like `{ type: "text", text: "..." }`.
+#[napi(object)]
+pub struct ContentPart {
+  #[napi(js_name = "type")]
+  pub part_type: String,
+  pub text: Option<String>,
+}
+
+#[napi(object)]
+pub struct EncodeResult {
+  pub count: i32,
+  /// Only set if the tokens were asked for.
+  pub tokens: Option<Vec<u32>>,
+}
+
+#[napi(object)]
+pub struct PoolStats {
+  /// Messages waiting for an actor, across all priority lanes.
+  pub queued: u32,
+  /// How many messages can wait before callers have to wait too.
+  pub capacity: u32,
+  /// Actor threads that haven't been joined by `close`.
+  pub actor_count: u32,
+  /// Messages waiting in the actors' own lanes, see `encode_with_affinity`. These are not part of
+  /// `queued` and `capacity`.
+  pub affinity_queued: u32,
+}
+
+#[napi(object)]
+pub struct ActorHealth {
+  /// Whether the actor thread is still running.
+  pub running: bool,
+  /// How many times the actor was restarted after a message made it panic.
+  pub restarts: u32,
+}
+
+#[napi(object)]
+pub struct TokenOffset16 {
+  pub token: u32,
+  /// Offset into the text in UTF-16 code units, like JS string indices, where the token starts.
+  pub start: u32,
+  /// Offset into the text in UTF-16 code units where the token ends, exclusive.
+  pub end: u32,
+}
+
+#[napi(object)]
+pub struct CountComparison {
+  pub exact: i32,
+  pub approx: i32,
+  /// `|exact - approx|`.
+  pub abs_error: i32,
+  /// `exact / approx`, with `approx` taken to be at least 1.
+  pub ratio: f64,
+}
+
+#[napi(object)]
+pub struct EncodingInfo {
+  pub name: String,
+  /// The number of tokens, including the special tokens.
+  pub vocab_size: u32,
+  pub num_special_tokens: u32,
+  /// Whether `encode_with_bos_eos` can add a beginning of text token.
+  pub has_bos: bool,
+  /// Whether `encode_with_bos_eos` can add an end of text token.
+  pub has_eos: bool,
+}
+
+#[napi(object)]
+pub struct TokenPiece {
+  pub token: u32,
+  /// The bytes the token stands for, which need not be valid UTF-8 on their own.
+  pub bytes: napi::bindgen_prelude::Uint8Array,
+}
+
+#[napi(object)]
+pub struct PackResult {
+  pub included_indices: Vec<u32>,
+  pub total_tokens: i32,
+  /// The included snippets joined by the separator.
+  pub text: String,
+}
+
+#[napi(object)]
+pub struct BenchResult {
+  pub total_tokens: i64,
+  pub elapsed_ms: f64,
+  pub tokens_per_sec: f64,
+}
+
+#[napi(object)]
+pub struct BatchStats {
+  pub counts: Vec<i32>,
+  pub total: i64,
+  /// 0 for an empty batch, like `min` and `mean`.
+  pub max: i32,
+  pub min: i32,
+  pub mean: f64,
+}
+
+#[napi]
+impl Tokenizer {
+  pub fn new() -> Result<Self, Error> {
+    Self::spawn(DEFAULT_NUM_ACTORS, 1, DEFAULT_QUEUE_CAPACITY, None)
+  }
+
+  /// Creates a tokenizer whose actors serve `num_priority_lanes` lanes, see
+  /// `encode_with_priority`. A single lane behaves exactly like `get_tokenizer()`.
+  #[napi(factory)]
+  pub fn with_priority_lanes(num_priority_lanes: u32) -> Result<Self, Error> {
+    Self::spawn(DEFAULT_NUM_ACTORS, num_priority_lanes as usize, DEFAULT_QUEUE_CAPACITY, None)
+  }
+
+  /// Creates a tokenizer with `num_threads` actor threads (at least 1) instead of the default 4.
+  /// Every actor still handles one message at a time, so more threads only help when there are
+  /// that many requests in flight concurrently.
+  #[napi(factory)]
+  pub fn with_thread_count(num_threads: u32) -> Result<Self, Error> {
+    Self::spawn(num_threads as usize, 1, DEFAULT_QUEUE_CAPACITY, None)
+  }
+
+  /// Creates a tokenizer with `num_threads` actor threads (at least 1) whose queue holds
+  /// `queue_capacity` messages (default 256) before calls wait for an actor to free up. A deeper
+  /// queue absorbs bursts, a shallow one pushes back on callers sooner, and makes `try_encode`
+  /// report `Overloaded` sooner. Every actor's own lane for `encode_with_affinity` holds
+  /// `queue_capacity` messages on top of that.
+  #[napi(factory)]
+  pub fn with_config(num_threads: u32, queue_capacity: Option<u32>) -> Result<Self, Error> {
+    let queue_capacity = queue_capacity.map_or(DEFAULT_QUEUE_CAPACITY, |c| c as usize);
+    if queue_capacity == 0 {
+      return Err(TokenizerErrorKind::InvalidArgument.error("queue_capacity must be at least 1"));
+    }
+    Self::spawn(num_threads as usize, 1, queue_capacity, None)
+  }
+
+  /// Creates a tokenizer that can also encode with the rank file at `path`, which has a base64
+  /// encoded token and its rank on every line, using `SupportedEncoding.Custom`. `pattern` is the
+  /// regex used to split text before merging.
+  #[napi(factory)]
+  pub fn from_tiktoken_file(
+    path: String,
+    special_tokens: HashMap<String, u32>,
+    pattern: String,
+  ) -> Result<Self, Error> {
+    let contents = std::fs::read_to_string(&path).map_err(|e| {
+      TokenizerErrorKind::InvalidArgument.error(format!("Failed to read {}: {}", path, e))
+    })?;
+    let mergeable_ranks = parse_tiktoken_ranks(&contents)
+      .map_err(|e| TokenizerErrorKind::InvalidArgument.error(format!("{}: {}", path, e)))?;
+    let custom_encoding = tiktoken::Encoding::new(
+      "custom",
+      &pattern,
+      mergeable_ranks.into_iter().collect(),
+      special_tokens.into_iter().map(|(k, v)| (k, v as usize)).collect(),
+      None,
+    )
+    .map_err(|e| TokenizerErrorKind::InvalidArgument.error(e))?;
+    Self::spawn(DEFAULT_NUM_ACTORS, 1, DEFAULT_QUEUE_CAPACITY, Some(Arc::new(custom_encoding)))
+  }
+
+  /// Starts the actor threads. If one of them can't be spawned, e.g. because the process hit its
+  /// thread limit, the ones already running are shut down again before the error is returned.
+  /// Every actor has its own affinity lane next to the shared lanes, see `encode_with_affinity`.
+  fn spawn(
+    num_actors: usize,
+    num_lanes: usize,
+    queue_capacity: usize,
+    custom_encoding: Option<Arc<tiktoken::Encoding>>,
+  ) -> Result<Self, Error> {
+    let encodings = ENCODINGS.clone();
+    let (sender, receivers) = priority_lanes(num_lanes, num_actors, queue_capacity);
+    let mut actors = vec![];
+    for (i, receiver) in receivers.into_iter().enumerate() {
+      let actor = TokenizerActor::new(receiver, encodings.clone(), custom_encoding.clone());
+      let restarts = Arc::new(AtomicU32::new(0));
+      let actor_restarts = restarts.clone();
+      let spawned = Builder::new_current_thread().build().and_then(|runtime| {
+        std::thread::Builder::new()
+          .name(format!("tokenizer-actor-{}", i))
+          .spawn(move || run_tokenizer_actor(actor, runtime, actor_restarts))
+      });
+      match spawned {
+        Ok(handle) => actors.push(ActorThread { handle, restarts }),
+        Err(e) => {
+          sender.close();
+          for actor in actors {
+            let _ = actor.handle.join();
+          }
+          return Err(
+            TokenizerErrorKind::ActorDead
+              .error(format!("Failed to spawn tokenizer actor thread: {}", e)),
+          );
+        }
+      }
+    }
+
+    Ok(Self { sender, actors: Arc::new(Mutex::new(actors)), encodings, custom_encoding })
+  }
+
+  /// Lets the actor threads finish the messages that are already queued, then resolves once they
+  /// have exited. Every call made afterwards fails with a "Tokenizer has been closed" error. This
+  /// closes every clone of the tokenizer, so don't call it on the shared `getTokenizer()` instance.
+  #[napi]
+  pub async fn close(&self) -> Result<(), Error> {
+    self.sender.close();
+    let actors = std::mem::take(&mut *self.actors.lock().unwrap_or_else(|e| e.into_inner()));
+    // joining blocks until the queued messages are handled, which must not hold up the event loop
+    tokio::task::spawn_blocking(move || {
+      for actor in actors {
+        let _ = actor.handle.join();
+      }
+    })
+    .await
+    .map_err(|e| {
+      TokenizerErrorKind::ActorDead.error(format!("Failed to join tokenizer actors: {}", e))
+    })
+  }
+
+  fn get_encoding(&self, encoding: SupportedEncoding) -> Result<&tiktoken::Encoding, Error> {
+    let loaded = self.encodings.get(encoding).map_err(|e| {
+      TokenizerErrorKind::EncodingError.error(format!("Failed to load {}: {}", encoding.name(), e))
+    })?;
+    match (loaded, &self.custom_encoding) {
+      (Some(encoding), _) => Ok(encoding),
+      (None, Some(custom_encoding)) => Ok(custom_encoding),
+      (None, None) => Err(TokenizerErrorKind::InvalidArgument.error(
+        "This tokenizer has no custom encoding, create one with Tokenizer.fromTiktokenFile",
+      )),
+    }
+  }
+
+  /// Sends the message to the actors and waits for its response.
+  async fn request<T>(
+    &self,
+    msg: TokenizerMessage,
+    recv: oneshot::Receiver<anyhow::Result<T>>,
+  ) -> Result<T, Error> {
+    self.request_with_priority(msg, recv, 0).await
+  }
+
+  async fn request_with_priority<T>(
+    &self,
+    msg: TokenizerMessage,
+    recv: oneshot::Receiver<anyhow::Result<T>>,
+    priority: u8,
+  ) -> Result<T, Error> {
+    if let Some(encoding) = msg.encoding() {
+      self.get_encoding(encoding)?;
+    }
+    // sending only fails once the channel has been closed
+    if self.sender.send_with_priority(msg, priority).await.is_err() {
+      return Err(TokenizerErrorKind::ChannelClosed.error("Tokenizer has been closed"));
+    }
+    Self::response(recv).await
+  }
+
+  /// Like `request`, but sends the message to the actor `affinity_key` picks, see
+  /// `LaneSender::send_to_actor`.
+  async fn request_with_affinity<T>(
+    &self,
+    msg: TokenizerMessage,
+    recv: oneshot::Receiver<anyhow::Result<T>>,
+    affinity_key: u32,
+  ) -> Result<T, Error> {
+    if let Some(encoding) = msg.encoding() {
+      self.get_encoding(encoding)?;
+    }
+    // sending only fails once the channel has been closed
+    if self.sender.send_to_actor(affinity_key as usize, msg).await.is_err() {
+      return Err(TokenizerErrorKind::ChannelClosed.error("Tokenizer has been closed"));
+    }
+    Self::response(recv).await
+  }
+
+  /// Like `request`, but fails with "Tokenizer overloaded" instead of waiting when the queue is
+  /// full.
+  async fn try_request<T>(
+    &self,
+    msg: TokenizerMessage,
+    recv: oneshot::Receiver<anyhow::Result<T>>,
+  ) -> Result<T, Error> {
+    if let Some(encoding) = msg.encoding() {
+      self.get_encoding(encoding)?;
+    }
+    match self.sender.try_send(msg) {
+      Ok(()) => Self::response(recv).await,
+      Err(TrySendError::Full(_)) => {
+        Err(TokenizerErrorKind::Overloaded.error("Tokenizer overloaded"))
+      }
+      Err(TrySendError::Closed(_)) => {
+        Err(TokenizerErrorKind::ChannelClosed.error("Tokenizer has been closed"))
+      }
+    }
+  }
+
+  /// Like `request`, but fails with "Tokenizer timed out" if the response takes longer than
+  /// `timeout`. Only waiting for the response counts, not waiting for room in the queue. A late
+  /// response is dropped by the actor when it finds nobody listening.
+  async fn request_with_timeout<T>(
+    &self,
+    msg: TokenizerMessage,
+    recv: oneshot::Receiver<anyhow::Result<T>>,
+    timeout: Duration,
+  ) -> Result<T, Error> {
+    if let Some(encoding) = msg.encoding() {
+      self.get_encoding(encoding)?;
+    }
+    // sending only fails once the channel has been closed
+    if self.sender.send(msg).await.is_err() {
+      return Err(TokenizerErrorKind::ChannelClosed.error("Tokenizer has been closed"));
+    }
+    match tokio::time::timeout(timeout, Self::response(recv)).await {
+      Ok(result) => result,
+      Err(_) => Err(TokenizerErrorKind::TimedOut.error("Tokenizer timed out")),
+    }
+  }
+
+  async fn response<T>(recv: oneshot::Receiver<anyhow::Result<T>>) -> Result<T, Error> {
+    match recv.await {
+      Ok(result) => result.map_err(|e| {
+        let kind =
+          e.downcast_ref::<ActorError>().map_or(TokenizerErrorKind::EncodingError, |e| e.kind);
+        kind.error(e)
+      }),
+      Err(e) => {
+        Err(TokenizerErrorKind::ActorDead.error(format!("Actor task has been killed: {}", e)))
+      }
+    }
+  }
+
+  #[napi]
+  pub async fn exact_num_tokens_no_special_tokens(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        // no special tokens!! everything is normal text
+        // this is how tokenization is handled in the chat model api
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// If `strip_control_chars` is set, the C0 control characters U+0000 through U+001F are removed
+  /// before encoding, except for tab (U+0009) and line feed (U+000A). Defaults to false.
+  ///
+  /// Overrides for strings that are not special tokens of the encoding are an error, unless
+  /// `ignore_unknown_overrides` is set.
+  #[napi]
+  pub async fn exact_num_tokens(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    special_token_default_action: SpecialTokenAction,
+    special_token_overrides: HashMap<String, SpecialTokenAction>,
+    strip_control_chars: Option<bool>,
+    ignore_unknown_overrides: Option<bool>,
+  ) -> Result<i32, Error> {
+    check_special_token_overrides(
+      self.get_encoding(encoding)?,
+      &special_token_overrides,
+      ignore_unknown_overrides.unwrap_or(false),
+    )?;
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        // no special tokens!! everything is normal text
+        // this is how tokenization is handled in the chat model api
+        default: special_token_default_action.to_tiktoken(),
+        overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
+      },
+      strip_control_chars: strip_control_chars.unwrap_or(false),
+    };
+
+    self.request(msg, recv).await
+  }
+
+  #[napi]
+  pub async fn encode_cl100k_no_special_tokens(&self, text: String) -> Result<Vec<u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding: SupportedEncoding::Cl100k,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        // no special tokens!! everything is normal text
+        // this is how tokenization is handled in the chat model api
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  #[napi]
+  pub async fn approx_num_tokens(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    replace_spaces_with_lower_one_eighth_block: bool,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ApproximateNumTokens {
+      respond_to: send,
+      text,
+      encoding,
+      replace_spaces_with_lower_one_eighth_block,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Like `approx_num_tokens`, but also returns bounds on the exact count from a fixed ratio per
+  /// encoding. Short texts get a few tokens of extra slack. The ratios are unvalidated guesses
+  /// rather than measured error, so don't rely on `upper` where going over it is unsafe.
+  #[napi]
+  pub async fn approx_num_tokens_with_bounds(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    replace_spaces_with_lower_one_eighth_block: bool,
+  ) -> Result<TokenEstimate, Error> {
+    let estimate =
+      self.approx_num_tokens(text, encoding, replace_spaces_with_lower_one_eighth_block).await?;
+    let (lower_ratio, upper_ratio) = encoding.estimate_ratio_bounds();
+
+    Ok(TokenEstimate {
+      estimate,
+      lower: (estimate as f64 * lower_ratio).floor() as i32,
+      upper: (estimate as f64 * upper_ratio).ceil() as i32 + ESTIMATE_UPPER_SLACK,
+    })
+  }
+
+  /// If `strip_control_chars` is set, the C0 control characters U+0000 through U+001F are removed
+  /// before encoding, except for tab (U+0009) and line feed (U+000A). Defaults to false.
+  ///
+  /// If `max_special_tokens` is set, an error is returned when the encoded text contains more
+  /// special tokens than that.
+  ///
+  /// Overrides for strings that are not special tokens of the encoding are an error, unless
+  /// `ignore_unknown_overrides` is set.
+  #[napi]
+  pub async fn encode(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    special_token_default_action: SpecialTokenAction,
+    special_token_overrides: HashMap<String, SpecialTokenAction>,
+    strip_control_chars: Option<bool>,
+    max_special_tokens: Option<u32>,
+    ignore_unknown_overrides: Option<bool>,
+  ) -> Result<Vec<u32>, Error> {
+    check_special_token_overrides(
+      self.get_encoding(encoding)?,
+      &special_token_overrides,
+      ignore_unknown_overrides.unwrap_or(false),
+    )?;
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        // no special tokens!! everything is normal text
+        // this is how tokenization is handled in the chat model api
+        default: special_token_default_action.to_tiktoken(),
+        overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
+      },
+      strip_control_chars: strip_control_chars.unwrap_or(false),
+      max_special_tokens,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  #[napi]
+  pub async fn encode_single_token(
+    &self,
+    bytes: napi::bindgen_prelude::Uint8Array,
+    encoding: SupportedEncoding,
+  ) -> Result<u32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg =
+      TokenizerMessage::EncodeSingleToken { respond_to: send, bytes: bytes.to_vec(), encoding };
+
+    self.request(msg, recv).await
+  }
+  #[napi]
+  pub async fn decode_byte(
+    &self,
+    token: u32,
+    encoding: SupportedEncoding,
+  ) -> Result<napi::bindgen_prelude::Uint8Array, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeTokenBytes { respond_to: send, token, encoding };
+
+    self.request(msg, recv).await.map(|v| napi::bindgen_prelude::Uint8Array::new(v.into()))
+  }
+
+  #[napi]
+  pub async fn decode(
+    &self,
+    encoded_tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  ) -> Result<String, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeTokens { respond_to: send, tokens: encoded_tokens, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Encodes the text with every loaded encoding, keyed by encoding name. Special tokens are
+  /// treated as normal text.
+  #[napi]
+  pub async fn encode_all(
+    &self,
+    text: String,
+  ) -> Result<HashMap<String, napi::bindgen_prelude::Uint32Array>, Error> {
+    // the actors expect every encoding they use to be loaded already
+    for encoding in SupportedEncoding::ALL {
+      self.get_encoding(encoding)?;
+    }
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeAll { respond_to: send, text };
+
+    self.request(msg, recv).await.map(|all_tokens| {
+      all_tokens
+        .into_iter()
+        .map(|(encoding, tokens)| {
+          (encoding.name().to_string(), napi::bindgen_prelude::Uint32Array::new(tokens))
+        })
+        .collect()
+    })
+  }
+
+  /// Counts the tokens of the added and removed lines of a unified diff, ignoring file headers,
+  /// hunk headers and context lines. Returns an error if the diff is malformed, including any
+  /// non-empty text without a hunk header.
+  #[napi]
+  pub async fn count_diff_changes(
+    &self,
+    unified_diff: String,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::CountDiffChanges { respond_to: send, unified_diff, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Encodes the text and returns the token ids joined by commas. Special tokens are treated as
+  /// normal text.
+  #[napi]
+  pub async fn encode_to_csv(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<String, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeToCsv { respond_to: send, text, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Decodes comma-separated token ids, as produced by `encode_to_csv`.
+  #[napi]
+  pub async fn decode_from_csv(
+    &self,
+    csv: String,
+    encoding: SupportedEncoding,
+  ) -> Result<String, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeFromCsv { respond_to: send, csv, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Counts the tokens of the standard (padded) base64 encoding of the bytes, which is what
+  /// inlining them in a data URI costs.
+  #[napi]
+  pub async fn num_tokens_base64(
+    &self,
+    bytes: napi::bindgen_prelude::Uint8Array,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg =
+      TokenizerMessage::NumTokensBase64 { respond_to: send, bytes: bytes.to_vec(), encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Returns the number of bytes the tokens decode to, without building the decoded string.
+  #[napi]
+  pub async fn decode_byte_len(
+    &self,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  ) -> Result<u32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeByteLen { respond_to: send, tokens, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Like `encode`, but the message jumps ahead of any queued messages with a lower priority.
+  /// Priorities go from 0 (the lane every other method uses) up to the number of lanes minus one,
+  /// higher ones are clamped. To avoid starving the lower lanes, every eighth message an actor picks
+  /// up is taken from the lowest non-empty lane instead.
+  ///
+  /// Unknown overrides are an error unless `ignore_unknown_overrides` is set, like in `encode`.
+  #[napi]
+  pub async fn encode_with_priority(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    priority: u8,
+    special_token_default_action: SpecialTokenAction,
+    special_token_overrides: HashMap<String, SpecialTokenAction>,
+    ignore_unknown_overrides: Option<bool>,
+  ) -> Result<Vec<u32>, Error> {
+    check_special_token_overrides(
+      self.get_encoding(encoding)?,
+      &special_token_overrides,
+      ignore_unknown_overrides.unwrap_or(false),
+    )?;
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: special_token_default_action.to_tiktoken(),
+        overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request_with_priority(msg, recv, priority).await
+  }
+
+  /// Returns the number of tokens divided by the number of characters (unicode scalar values, not
+  /// bytes), or 0 for an empty text. Special tokens are treated as normal text.
+  #[napi]
+  pub async fn tokens_per_char(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<f64, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::TokensPerChar { respond_to: send, text, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Groups the sentences of the text into chunks of at most `max_tokens` tokens each. Sentences end
+  /// at `.`, `!` or `?` followed by whitespace, and are only split if a single sentence is over the
+  /// limit. Special tokens are treated as normal text.
+  ///
+  /// A chunk's `num_tokens` is the sum of the counts of its sentences, which can differ slightly
+  /// from encoding the chunk as a whole.
+  #[napi]
+  pub async fn split_tokens_by_sentence(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    max_tokens: u32,
+  ) -> Result<Vec<Chunk>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg =
+      TokenizerMessage::SplitTokensBySentence { respond_to: send, text, encoding, max_tokens };
+
+    self.request(msg, recv).await
+  }
+
+  /// Returns the distinct tokens of the text, sorted ascending. Special tokens are treated as normal
+  /// text.
+  #[napi]
+  pub async fn unique_tokens(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::UniqueTokens { respond_to: send, text, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Returns the number of distinct tokens of the text. Special tokens are treated as normal text.
+  #[napi]
+  pub async fn unique_token_count(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<u32, Error> {
+    self.unique_tokens(text, encoding).await.map(|t| t.len() as u32)
+  }
+
+  /// Whether the bytes the encoded text decodes to are exactly the bytes of the text. Special
+  /// tokens are treated as normal text.
+  #[napi]
+  pub async fn is_lossless(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<bool, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::IsLossless { respond_to: send, text, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Encodes all texts in a single actor message, in order. Fails if any of them fails, with the
+  /// index of the offending text in the error. Unknown overrides are an error unless
+  /// `ignore_unknown_overrides` is set, like in `encode`.
+  #[napi]
+  pub async fn encode_batch(
+    &self,
+    texts: Vec<String>,
+    encoding: SupportedEncoding,
+    special_token_default_action: SpecialTokenAction,
+    special_token_overrides: HashMap<String, SpecialTokenAction>,
+    ignore_unknown_overrides: Option<bool>,
+  ) -> Result<Vec<Vec<u32>>, Error> {
+    check_special_token_overrides(
+      self.get_encoding(encoding)?,
+      &special_token_overrides,
+      ignore_unknown_overrides.unwrap_or(false),
+    )?;
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeBatch {
+      respond_to: send,
+      texts,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: special_token_default_action.to_tiktoken(),
+        overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
+      },
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Counts the tokens of all texts in a single actor message, in order. Fails if any of them
+  /// fails, with the index of the offending text in the error. Unknown overrides are an error
+  /// unless `ignore_unknown_overrides` is set, like in `exact_num_tokens`.
+  #[napi]
+  pub async fn exact_num_tokens_batch(
+    &self,
+    texts: Vec<String>,
+    encoding: SupportedEncoding,
+    special_token_default_action: SpecialTokenAction,
+    special_token_overrides: HashMap<String, SpecialTokenAction>,
+    ignore_unknown_overrides: Option<bool>,
+  ) -> Result<Vec<i32>, Error> {
+    check_special_token_overrides(
+      self.get_encoding(encoding)?,
+      &special_token_overrides,
+      ignore_unknown_overrides.unwrap_or(false),
+    )?;
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokensBatch {
+      respond_to: send,
+      texts,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: special_token_default_action.to_tiktoken(),
+        overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
+      },
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Counts the tokens an OpenAI chat request with these messages costs, including the
+  /// per-message framing and the reply priming. Only supported for cl100k and o200k.
+  #[napi]
+  pub async fn num_tokens_for_chat(
+    &self,
+    messages: Vec<ChatMessage>,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    match encoding {
+      SupportedEncoding::Cl100k | SupportedEncoding::O200k => {
+        self
+          .num_tokens_for_chat_custom(
+            messages,
+            encoding,
+            CHAT_TOKENS_PER_MESSAGE,
+            CHAT_TOKENS_PER_NAME,
+            CHAT_REPLY_PRIMING_TOKENS,
+          )
+          .await
+      }
+      _ => Err(
+        TokenizerErrorKind::InvalidArgument
+          .error(format!("Chat token counting is not supported for {}", encoding.name())),
+      ),
+    }
+  }
+
+  /// Like `num_tokens_for_chat`, but with the framing overhead given by the caller: every message
+  /// costs `tokens_per_message` on top of its role and content, a name costs `tokens_per_name` on
+  /// top of the name itself, and the reply priming costs `reply_priming`. Works with any encoding.
+  #[napi]
+  pub async fn num_tokens_for_chat_custom(
+    &self,
+    messages: Vec<ChatMessage>,
+    encoding: SupportedEncoding,
+    tokens_per_message: i32,
+    tokens_per_name: i32,
+    reply_priming: i32,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::NumTokensForChat {
+      respond_to: send,
+      messages,
+      encoding,
+      tokens_per_message,
+      tokens_per_name,
+      reply_priming,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Truncates the text to its first (or last, if `from_end`) `max_tokens` tokens. Text that already
+  /// fits is returned unchanged, and the cut never splits a character, so the result can be a few
+  /// tokens shorter than `max_tokens`. Special tokens are treated as normal text.
+  #[napi]
+  pub async fn truncate_to_tokens(
+    &self,
+    text: String,
+    max_tokens: u32,
+    encoding: SupportedEncoding,
+    from_end: bool,
+  ) -> Result<String, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg =
+      TokenizerMessage::TruncateToTokens { respond_to: send, text, max_tokens, encoding, from_end };
+
+    self.request(msg, recv).await
+  }
+
+  /// Encodes the text and returns every token with the byte range of the text it covers. A token
+  /// that only covers part of a multi-byte character gets the range of the whole character. Special
+  /// tokens are treated as normal text.
+  #[napi]
+  pub async fn encode_with_offsets(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<TokenOffset>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg =
+      TokenizerMessage::EncodeWithOffsets { respond_to: send, text, encoding, utf16: false };
+
+    self.request(msg, recv).await
+  }
+
+  /// Splits the text into chunks of `chunk_size` tokens that overlap by `overlap` tokens; the last
+  /// chunk may be shorter. Chunks are slices of the text, so a chunk boundary inside a multi-byte
+  /// character includes the whole character. Special tokens are treated as normal text.
+  #[napi]
+  pub async fn chunk_by_tokens(
+    &self,
+    text: String,
+    chunk_size: u32,
+    overlap: u32,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<String>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg =
+      TokenizerMessage::ChunkByTokens { respond_to: send, text, chunk_size, overlap, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// The number of tokens in the encoding, including the special tokens.
+  #[napi]
+  pub fn vocab_size(&self, encoding: SupportedEncoding) -> Result<u32, Error> {
+    Ok(vocab_size(self.get_encoding(encoding)?))
+  }
+
+  /// The special token strings of the encoding, sorted.
+  #[napi]
+  pub fn special_tokens(&self, encoding: SupportedEncoding) -> Result<Vec<String>, Error> {
+    Ok(special_tokens(self.get_encoding(encoding)?))
+  }
+
+  /// Decodes the tokens, replacing invalid UTF-8 (like a sequence that ends mid-character) with
+  /// U+FFFD instead of failing. If `replacement` is set, it is used instead of U+FFFD; the empty
+  /// string drops invalid bytes altogether.
+  #[napi]
+  pub async fn decode_lossy(
+    &self,
+    encoded_tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+    replacement: Option<String>,
+  ) -> Result<String, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeLossy {
+      respond_to: send,
+      tokens: encoded_tokens,
+      encoding,
+      replacement,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Decodes all token sequences in a single actor message, in order.
+  #[napi]
+  pub async fn decode_batch(
+    &self,
+    sequences: Vec<Vec<u32>>,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<String>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeBatch { respond_to: send, sequences, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but fails with "Encoding was
+  /// cancelled" once `token.cancel()` is called. Large texts are encoded in chunks of about 64KiB
+  /// that end at a newline or before a space, and cancellation is checked between chunks.
+  #[napi(ts_return_type = "Promise<Array<number>>")]
+  pub fn encode_cancellable(
+    &self,
+    env: Env,
+    text: String,
+    encoding: SupportedEncoding,
+    token: &CancellationToken,
+  ) -> Result<JsObject, Error> {
+    let tokenizer = self.clone();
+    let cancelled = token.cancelled.clone();
+    env.spawn_future(async move {
+      let (send, recv) = oneshot::channel();
+      let msg = TokenizerMessage::EncodeCancellable { respond_to: send, text, encoding, cancelled };
+
+      tokenizer.request(msg, recv).await
+    })
+  }
+
+  /// Returns whether the text is at least `limit` tokens long, with special tokens treated as
+  /// normal text. Encoding stops at the first chunk (see `encode_cancellable`) that reaches the
+  /// limit, so large texts are not tokenized in full.
+  #[napi]
+  pub async fn num_tokens_at_least(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    limit: u32,
+  ) -> Result<bool, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::NumTokensAtLeast { respond_to: send, text, encoding, limit };
+
+    self.request(msg, recv).await
+  }
+
+  /// Encodes the text like `encode_cancellable` and calls `callback(null, tokens, false)` with the
+  /// tokens of every chunk as soon as it is encoded. The last call is `callback(null, [], true)`
+  /// once the whole text is done, or `callback(err)` if encoding failed.
+  #[napi(
+    ts_args_type = "text: string, encoding: SupportedEncoding, callback: (err: Error | null, tokens: Array<number>, done: boolean) => void"
+  )]
+  pub fn encode_streaming(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    callback: JsFunction,
+  ) -> Result<(), Error> {
+    let callback: ThreadsafeFunction<(Vec<u32>, bool), ErrorStrategy::CalleeHandled> = callback
+      .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<(Vec<u32>, bool)>| {
+        let (tokens, done) = ctx.value;
+        let mut array = ctx.env.create_array_with_length(tokens.len())?;
+        for (i, token) in tokens.into_iter().enumerate() {
+          array.set_element(i as u32, ctx.env.create_uint32(token)?)?;
+        }
+        Ok(vec![array.into_unknown(), ctx.env.get_boolean(done)?.into_unknown()])
+      })?;
+
+    let tokenizer = self.clone();
+    napi::bindgen_prelude::spawn(async move {
+      let (send, recv) = oneshot::channel();
+      let msg = TokenizerMessage::EncodeStreaming {
+        respond_to: send,
+        text,
+        encoding,
+        callback: callback.clone(),
+      };
+
+      // the actor has queued all chunk calls before responding, so this call comes last
+      let result = tokenizer.request(msg, recv).await.map(|()| (vec![], true));
+      callback.call(result, ThreadsafeFunctionCallMode::NonBlocking);
+    });
+    Ok(())
+  }
+
+  /// Same result as `encode` with special tokens treated as normal text, but large texts are split
+  /// into chunks at boundaries no merge can cross (see `encode_cancellable`) and the chunks are
+  /// encoded concurrently by all actors. Texts without such boundaries are encoded by one actor.
+  #[napi]
+  pub async fn encode_parallel(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<u32>, Error> {
+    self.get_encoding(encoding)?;
+    let chunks = encoding_chunks(&text, encoding);
+
+    let handles = chunks
+      .into_iter()
+      .map(|chunk| {
+        let tokenizer = self.clone();
+        let (send, recv) = oneshot::channel();
+        let msg = TokenizerMessage::EncodeTokens {
+          respond_to: send,
+          text: chunk.to_string(),
+          encoding,
+          special_token_handling: tiktoken::SpecialTokenHandling {
+            default: tiktoken::SpecialTokenAction::NormalText,
+            ..Default::default()
+          },
+          strip_control_chars: false,
+          max_special_tokens: None,
+        };
+        napi::bindgen_prelude::spawn(async move { tokenizer.request(msg, recv).await })
+      })
+      .collect::<Vec<_>>();
+
+    let mut tokens = vec![];
+    for handle in handles {
+      let chunk_tokens = handle
+        .await
+        .map_err(|e| TokenizerErrorKind::ActorDead.error(format!("Encode task failed: {}", e)))??;
+      tokens.extend(chunk_tokens);
+    }
+    Ok(tokens)
+  }
+
+  /// Counts the tokens of the text parts of a multimodal message content, with special tokens
+  /// treated as normal text. Parts of any other type, like `image_url`, count as 0 tokens for now,
+  /// since what an image costs depends on the model and its detail setting.
+  #[napi]
+  pub async fn num_tokens_for_content_parts(
+    &self,
+    parts: Vec<ContentPart>,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let texts = parts
+      .into_iter()
+      .filter(|part| part.part_type == "text")
+      .filter_map(|part| part.text)
+      .collect::<Vec<_>>();
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokensBatch {
+      respond_to: send,
+      texts,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+    };
+
+    self.request(msg, recv).await.map(|counts| counts.into_iter().sum())
+  }
+
+  /// The raw bytes of the tokens, concatenated. Unlike `decode` this keeps a character that is
+  /// split across the end of the sequence intact, so streamed completions can be joined exactly.
+  #[napi]
+  pub async fn decode_bytes(
+    &self,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  ) -> Result<napi::bindgen_prelude::Uint8Array, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeBytes { respond_to: send, tokens, encoding };
+
+    self.request(msg, recv).await.map(|v| napi::bindgen_prelude::Uint8Array::new(v.into()))
+  }
+
+  /// Encodes the text with special tokens treated as normal text and returns the token count,
+  /// plus the tokens themselves if `include_tokens` is set. Leaving them out saves converting a
+  /// large array to JS when only the count is needed.
+  #[napi]
+  pub async fn encode_and_count(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    include_tokens: bool,
+  ) -> Result<EncodeResult, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request(msg, recv).await.map(|tokens| EncodeResult {
+      count: tokens.len() as i32,
+      tokens: include_tokens.then_some(tokens),
+    })
+  }
+
+  /// Like `decode`, but fails naming the first token (and its index) that the encoding doesn't
+  /// have, neither as a regular nor as a special token, instead of decoding garbage.
+  #[napi]
+  pub async fn decode_checked(
+    &self,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  ) -> Result<String, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeChecked { respond_to: send, tokens, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// A cheap snapshot of the actor pool's queue, read without sending a message.
+  #[napi]
+  pub fn pool_stats(&self) -> PoolStats {
+    PoolStats {
+      queued: self.sender.len() as u32,
+      capacity: self.sender.capacity() as u32,
+      actor_count: self.actors.lock().unwrap_or_else(|e| e.into_inner()).len() as u32,
+      affinity_queued: self.sender.affinity_len() as u32,
+    }
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but fails right away with
+  /// "Tokenizer overloaded" when the queue is full instead of waiting for room, so callers can
+  /// shed load.
+  #[napi]
+  pub async fn try_encode(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.try_request(msg, recv).await
+  }
+
+  /// The regex the encoding splits text with before applying BPE, as tiktoken has it.
+  #[napi]
+  pub fn split_pattern(&self, encoding: SupportedEncoding) -> Result<String, Error> {
+    Ok(self.get_encoding(encoding)?.pat_str.clone())
+  }
+
+  /// Splits the text into the pieces the encoding's split regex produces, without merging them
+  /// into tokens. Special tokens are not recognized. The pieces concatenate to the text.
+  #[napi]
+  pub async fn pretokenize(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<String>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::Pretokenize { respond_to: send, text, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Adds the token count of `appended_text`, with special tokens treated as normal text, to the
+  /// count of the text before it, without recounting that text. Only exact if appending doesn't
+  /// change how the end of the previous text tokenizes, which holds when `appended_text` starts
+  /// with whitespace after a non-whitespace character, such as a new message on its own line.
+  #[napi]
+  pub async fn num_tokens_incremental(
+    &self,
+    previous_count: i32,
+    appended_text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokens {
+      respond_to: send,
+      text: appended_text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+    };
+
+    self.request(msg, recv).await.map(|delta| previous_count + delta)
+  }
+
+  /// The health of each actor thread, in spawn order. Actors restart themselves after a panic, so a
+  /// panicking actor shows up as a growing `restarts` count while `running` stays true; `running`
+  /// is only false for a thread that is gone for good. Empty after `close`.
+  #[napi]
+  pub fn actor_health(&self) -> Vec<ActorHealth> {
+    let actors = self.actors.lock().unwrap_or_else(|e| e.into_inner());
+    actors
+      .iter()
+      .map(|actor| ActorHealth {
+        running: !actor.handle.is_finished(),
+        restarts: actor.restarts.load(Ordering::Relaxed),
+      })
+      .collect()
+  }
+
+  /// Counts the tokens of the UTF-8 file at `path`, with special tokens treated as normal text.
+  /// The actor reads the file in pieces, so its contents never have to go through JS.
+  #[napi]
+  pub async fn num_tokens_in_file(
+    &self,
+    path: String,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::NumTokensInFile { respond_to: send, path, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Like `encode_with_offsets`, but the offsets are in UTF-16 code units, so they can be passed
+  /// straight to `String.prototype.slice`. Characters outside the BMP, like most emoji, count as
+  /// two units.
+  #[napi]
+  pub async fn encode_with_utf16_offsets(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<TokenOffset16>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeWithOffsets { respond_to: send, text, encoding, utf16: true };
+
+    self.request(msg, recv).await.map(|offsets| {
+      offsets
+        .into_iter()
+        .map(|o| TokenOffset16 { token: o.token, start: o.start, end: o.end })
+        .collect()
+    })
+  }
+
+  /// Whether the text, with special tokens treated as normal text, fits in the context window of
+  /// the model, like `gpt-4o` or `gpt-4-0314`. The fast estimate decides unless it is within 10% of
+  /// the window, in which case the tokens are counted exactly. Fails for unknown models.
+  #[napi]
+  pub async fn fits_in_model(&self, text: String, model: String) -> Result<bool, Error> {
+    let encoding = encoding_for_model(model.clone())?;
+    let context_window = context_window_for_model(&model).ok_or_else(|| {
+      TokenizerErrorKind::InvalidArgument.error(format!("Unknown context window for {}", model))
+    })? as f64;
+
+    let estimate =
+      self.get_encoding(encoding)?.estimate_num_tokens_no_special_tokens_fast(&text, false) as f64;
+    if estimate < context_window * (1.0 - FITS_ESTIMATE_MARGIN) {
+      return Ok(true);
+    }
+    if estimate > context_window * (1.0 + FITS_ESTIMATE_MARGIN) {
+      return Ok(false);
+    }
+
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+    };
+
+    self.request(msg, recv).await.map(|num_tokens| num_tokens as f64 <= context_window)
+  }
+
+  /// Encodes the text with special tokens treated as normal text, then adds the encoding's
+  /// beginning of text token in front if `add_bos` is set and its end of text token at the end if
+  /// `add_eos` is set. Fails if the encoding has no such token, like cl100k which has no BOS.
+  #[napi]
+  pub async fn encode_with_bos_eos(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    add_bos: bool,
+    add_eos: bool,
+  ) -> Result<Vec<u32>, Error> {
+    let special_tokens = &self.get_encoding(encoding)?.special_tokens;
+    let special_token = |add: bool, name: Option<&str>, kind: &str| -> Result<Option<u32>, Error> {
+      if !add {
+        return Ok(None);
+      }
+      name.and_then(|name| special_tokens.get(name)).map(|&token| Some(token as u32)).ok_or_else(
+        || {
+          TokenizerErrorKind::InvalidArgument.error(format!(
+            "{} has no {} token",
+            encoding.name(),
+            kind
+          ))
+        },
+      )
+    };
+    let bos = special_token(add_bos, encoding.bos_token(), "BOS")?;
+    let eos = special_token(add_eos, encoding.eos_token(), "EOS")?;
+
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    let tokens = self.request(msg, recv).await?;
+    Ok(bos.into_iter().chain(tokens).chain(eos).collect())
+  }
+
+  /// Like `truncate_to_tokens` keeping the start of the text, but if the text has to be cut, the
+  /// cut is moved back to the end of the last sentence (a `.`, `!` or `?` followed by whitespace)
+  /// in the kept text. A sentence that ends right at the cut is kept. Falls back to the plain cut
+  /// if the kept text has no sentence end.
+  #[napi]
+  pub async fn truncate_to_tokens_sentence_aware(
+    &self,
+    text: String,
+    max_tokens: u32,
+    encoding: SupportedEncoding,
+  ) -> Result<String, Error> {
+    // the kept text is a prefix of the text, which tells whether its last character ends a sentence
+    let truncated = self.truncate_to_tokens(text.clone(), max_tokens, encoding, false).await?;
+    if truncated.len() == text.len() {
+      return Ok(truncated);
+    }
+    Ok(match last_sentence_end(&text, truncated.len()) {
+      Some(end) => truncated[..end].to_string(),
+      None => truncated,
+    })
+  }
+
+  /// The number of leading tokens the two sequences have in common. Works on ids alone, so it
+  /// doesn't take an encoding.
+  #[napi]
+  pub async fn common_prefix_len(&self, a: Vec<u32>, b: Vec<u32>) -> Result<u32, Error> {
+    Ok(a.iter().zip(&b).take_while(|(a, b)| a == b).count() as u32)
+  }
+
+  /// Like `decode`, but drops the encoding's special tokens, like `<|im_start|>`, first. The rest
+  /// is decoded together, so a character split across the tokens around a special token is kept
+  /// whole.
+  #[napi]
+  pub async fn decode_without_special(
+    &self,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  ) -> Result<String, Error> {
+    let special_tokens: FxHashSet<u32> =
+      self.get_encoding(encoding)?.special_tokens.values().map(|&t| t as u32).collect();
+    let tokens = tokens.into_iter().filter(|t| !special_tokens.contains(t)).collect();
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeTokens { respond_to: send, tokens, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Whether `decode(encode(text))` gives back the text, with special tokens treated as normal
+  /// text. A mismatch returns false rather than failing, so callers can log the offending input.
+  /// Since the text is valid UTF-8, this is the same check as `is_lossless`.
+  #[napi]
+  pub async fn verify_roundtrip(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<bool, Error> {
+    self.is_lossless(text, encoding).await
+  }
+
+  /// How often every token occurs across all texts, with special tokens treated as normal text.
+  /// JS object keys are strings, so the token ids are the keys in decimal.
+  #[napi]
+  pub async fn token_histogram(
+    &self,
+    texts: Vec<String>,
+    encoding: SupportedEncoding,
+  ) -> Result<HashMap<String, u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::TokenHistogram { respond_to: send, texts, encoding };
+
+    self.request(msg, recv).await.map(|histogram| {
+      histogram.into_iter().map(|(token, count)| (token.to_string(), count)).collect()
+    })
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but if
+  /// `replace_spaces_with_lower_one_eighth_block` is set, every space is first replaced by U+2581
+  /// (▁), like `approx_num_tokens` does with the same flag. That changes the tokens, so this is only
+  /// meant for calibrating the estimate against exact counts, never for feeding a model.
+  #[napi]
+  pub async fn encode_with_space_replacement(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    replace_spaces_with_lower_one_eighth_block: Option<bool>,
+  ) -> Result<Vec<u32>, Error> {
+    let text = if replace_spaces_with_lower_one_eighth_block.unwrap_or(false) {
+      text.replace(' ', "\u{2581}")
+    } else {
+      text
+    };
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Counts the tokens exactly, with special tokens treated as normal text, and estimates them
+  /// like `approx_num_tokens`, on the same actor in one message.
+  #[napi]
+  pub async fn compare_counts(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<CountComparison, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::CompareCounts { respond_to: send, text, encoding };
+
+    self.request(msg, recv).await.map(|(exact, approx)| CountComparison {
+      exact: exact as i32,
+      approx: approx as i32,
+      abs_error: exact.abs_diff(approx) as i32,
+      ratio: exact as f64 / approx.max(1) as f64,
+    })
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but fails with "Tokenizer timed
+  /// out" if the actors haven't responded within `timeout_ms` after the request was queued.
+  #[napi]
+  pub async fn encode_with_timeout(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    timeout_ms: u32,
+  ) -> Result<Vec<u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request_with_timeout(msg, recv, Duration::from_millis(timeout_ms as u64)).await
+  }
+
+  /// The name, vocabulary size and special token counts of the encoding in one cheap read.
+  #[napi]
+  pub fn encoding_info(&self, encoding: SupportedEncoding) -> Result<EncodingInfo, Error> {
+    let loaded = self.get_encoding(encoding)?;
+    let has_special_token =
+      |name: Option<&str>| name.is_some_and(|name| loaded.special_tokens.contains_key(name));
+    Ok(EncodingInfo {
+      name: encoding.name().to_string(),
+      vocab_size: vocab_size(loaded),
+      num_special_tokens: loaded.special_tokens.len() as u32,
+      has_bos: has_special_token(encoding.bos_token()),
+      has_eos: has_special_token(encoding.eos_token()),
+    })
+  }
+
+  /// Encodes the text, with special tokens treated as normal text, and pairs every token with the
+  /// bytes it stands for.
+  #[napi]
+  pub async fn encode_with_pieces(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<TokenPiece>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeWithPieces { respond_to: send, text, encoding };
+
+    self.request(msg, recv).await.map(|pieces| {
+      pieces
+        .into_iter()
+        .map(|(token, bytes)| TokenPiece {
+          token,
+          bytes: napi::bindgen_prelude::Uint8Array::new(bytes),
+        })
+        .collect()
+    })
+  }
+
+  /// Counts the tokens of the text, with special tokens treated as normal text, after deleting
+  /// every occurrence of each string in `remove`, longest first.
+  #[napi]
+  pub async fn num_tokens_after_removing(
+    &self,
+    text: String,
+    remove: Vec<String>,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::NumTokensAfterRemoving { respond_to: send, text, remove, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// The indices of the texts that are at most `max_tokens` tokens long, with special tokens
+  /// treated as normal text. Texts stop being encoded once they go over.
+  #[napi]
+  pub async fn filter_under_budget(
+    &self,
+    texts: Vec<String>,
+    max_tokens: u32,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::FilterUnderBudget { respond_to: send, texts, max_tokens, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Takes snippets from the front of the list, joined by `separator`, until the next one would
+  /// take the total over `max_tokens`. The separator only counts between snippets. Snippets and
+  /// separators are counted separately, with special tokens treated as normal text, so the count of
+  /// the joined text can differ slightly where they meet.
+  #[napi]
+  pub async fn pack_under_budget(
+    &self,
+    snippets: Vec<String>,
+    max_tokens: u32,
+    separator: String,
+    encoding: SupportedEncoding,
+  ) -> Result<PackResult, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::PackUnderBudget {
+      respond_to: send,
+      snippets,
+      max_tokens,
+      separator,
+      encoding,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Counts the tokens in the text, with special tokens treated as normal text, but stops at the
+  /// first chunk (see `encode_cancellable`) that reaches `cap`. The result is exactly `cap` when
+  /// the text has at least `cap` tokens, and the exact count otherwise.
+  #[napi]
+  pub async fn num_tokens_capped(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    cap: u32,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::NumTokensCapped { respond_to: send, text, encoding, cap };
+
+    self.request(msg, recv).await
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but applies Unicode NFC
+  /// normalization first, so decomposed characters like "e" followed by a combining acute accent
+  /// are encoded as the composed "é". This can give a different number of tokens than encoding the
+  /// text as is, so only use it where the other side normalizes too.
+  #[napi]
+  pub async fn encode_nfc(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text: text.nfc().collect(),
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// The cost of the text's tokens, counted like `exact_num_tokens_no_special_tokens`, at
+  /// `price_per_1k_tokens` per 1000 tokens.
+  #[napi]
+  pub async fn estimate_cost(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    price_per_1k_tokens: f64,
+  ) -> Result<f64, Error> {
+    let num_tokens = self.exact_num_tokens_no_special_tokens(text, encoding).await?;
+    Ok(num_tokens as f64 * price_per_1k_tokens / 1000.0)
+  }
+
+  /// The cost of a chat request: the prompt, counted like `num_tokens_for_chat`, at
+  /// `input_price_per_1k` per 1000 tokens, plus `output_token_estimate` reply tokens at
+  /// `output_price_per_1k` per 1000 tokens. Only supported for cl100k and o200k.
+  #[napi]
+  pub async fn estimate_chat_cost(
+    &self,
+    messages: Vec<ChatMessage>,
+    encoding: SupportedEncoding,
+    input_price_per_1k: f64,
+    output_token_estimate: u32,
+    output_price_per_1k: f64,
+  ) -> Result<f64, Error> {
+    let input_tokens = self.num_tokens_for_chat(messages, encoding).await?;
+    Ok(
+      (input_tokens as f64 * input_price_per_1k
+        + output_token_estimate as f64 * output_price_per_1k)
+        / 1000.0,
+    )
+  }
+
+  /// Decodes every token on its own, for showing tokens side by side. A token that is only part
+  /// of a character decodes to U+FFFD instead of failing, so joining the pieces doesn't always
+  /// give the same text as `decode`.
+  #[napi]
+  pub async fn decode_to_pieces(
+    &self,
+    tokens: Vec<u32>,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<String>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::DecodeToPieces { respond_to: send, tokens, encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but takes the text as UTF-8 bytes,
+  /// like the contents of a file read into a `Buffer`, without converting them to a string first.
+  /// Fails with `InvalidArgument` if the bytes are not valid UTF-8.
+  #[napi]
+  pub async fn encode_bytes(
+    &self,
+    bytes: napi::bindgen_prelude::Uint8Array,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<u32>, Error> {
+    let text = String::from_utf8(bytes.to_vec())
+      .map_err(|e| TokenizerErrorKind::InvalidArgument.error(format!("Invalid UTF-8: {}", e)))?;
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Encodes the sample `iterations` times, one request after the other, with special tokens
+  /// treated as normal text, and reports the throughput. Every request goes through the actor queue
+  /// like any other call, so the numbers include the messaging overhead.
+  #[napi]
+  pub async fn benchmark(
+    &self,
+    sample_text: String,
+    encoding: SupportedEncoding,
+    iterations: u32,
+  ) -> Result<BenchResult, Error> {
+    let start = Instant::now();
+    let mut total_tokens = 0;
+    for _ in 0..iterations {
+      let (send, recv) = oneshot::channel();
+      let msg = TokenizerMessage::EncodeTokens {
+        respond_to: send,
+        text: sample_text.clone(),
+        encoding,
+        special_token_handling: tiktoken::SpecialTokenHandling {
+          default: tiktoken::SpecialTokenAction::NormalText,
+          ..Default::default()
+        },
+        strip_control_chars: false,
+        max_special_tokens: None,
+      };
+      total_tokens += self.request(msg, recv).await?.len() as i64;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    Ok(BenchResult {
+      total_tokens,
+      elapsed_ms: elapsed * 1000.0,
+      tokens_per_sec: if elapsed > 0.0 { total_tokens as f64 / elapsed } else { 0.0 },
+    })
+  }
+
+  /// Counts the tokens of all texts in a single actor message, with special tokens treated as
+  /// normal text, and aggregates the counts.
+  #[napi]
+  pub async fn batch_token_stats(
+    &self,
+    texts: Vec<String>,
+    encoding: SupportedEncoding,
+  ) -> Result<BatchStats, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokensBatch {
+      respond_to: send,
+      texts,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+    };
+
+    self.request(msg, recv).await.map(batch_stats)
+  }
+
+  /// Adds special tokens, like `{ "<|tool_call|>": 100300 }`, to a built-in encoding. Neither the
+  /// strings nor the ids may already be in the encoding's vocabulary.
+  ///
+  /// This changes the encoding for every tokenizer in the process, including `getTokenizer()`,
+  /// and only works before the encoding is first used, so call it once at startup.
+  #[napi]
+  pub fn add_special_tokens(
+    &self,
+    encoding: SupportedEncoding,
+    tokens: HashMap<String, u32>,
+  ) -> Result<(), Error> {
+    let lazy = self.encodings.lazy(encoding).ok_or_else(|| {
+      TokenizerErrorKind::InvalidArgument
+        .error("Pass the special tokens of a custom encoding to Tokenizer.fromTiktokenFile instead")
+    })?;
+    lazy.load_with_special_tokens(
+      encoding.name(),
+      tokens.into_iter().map(|(k, v)| (k, v as usize)).collect(),
+    )
+  }
+
+  /// Counts the tokens of every line in a single actor message, with special tokens treated as
+  /// normal text. Lines are split on `\n`, which is not counted, and empty lines count 0.
+  #[napi]
+  pub async fn line_token_counts(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<i32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::ExactNumTokensBatch {
+      respond_to: send,
+      texts: text.split('\n').map(String::from).collect(),
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but returns a `Uint32Array`, which
+  /// is much cheaper to hand to JS than a plain array for long texts.
+  #[napi]
+  pub async fn encode_typed(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+  ) -> Result<napi::bindgen_prelude::Uint32Array, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request(msg, recv).await.map(napi::bindgen_prelude::Uint32Array::new)
+  }
+
+  /// Like `decode`, but takes the tokens as a `Uint32Array`, like the ones `encode_typed` returns.
+  #[napi]
+  pub async fn decode_typed(
+    &self,
+    tokens: napi::bindgen_prelude::Uint32Array,
+    encoding: SupportedEncoding,
+  ) -> Result<String, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg =
+      TokenizerMessage::DecodeTokens { respond_to: send, tokens: tokens.to_vec(), encoding };
+
+    self.request(msg, recv).await
+  }
+
+  /// The index of the first token, with special tokens treated as normal text, whose text covers
+  /// the character at `char_offset`, given in UTF-16 code units like a JS string index. Returns -1
+  /// if the offset is past the end of the text.
+  #[napi]
+  pub async fn token_index_at_char(
+    &self,
+    text: String,
+    char_offset: u32,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeWithOffsets { respond_to: send, text, encoding, utf16: true };
+
+    self.request(msg, recv).await.map(|offsets| {
+      offsets
+        .iter()
+        .position(|o| o.start <= char_offset && char_offset < o.end)
+        .map_or(-1, |i| i as i32)
+    })
+  }
+
+  /// Like `encode_single_token` for every byte string, in a single actor message. Byte strings
+  /// that are not a single token give -1 instead of failing the whole batch.
+  #[napi]
+  pub async fn encode_single_tokens_batch(
+    &self,
+    byte_strings: Vec<napi::bindgen_prelude::Uint8Array>,
+    encoding: SupportedEncoding,
+  ) -> Result<Vec<i32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeSingleTokensBatch {
+      respond_to: send,
+      byte_strings: byte_strings.iter().map(|bytes| bytes.to_vec()).collect(),
+      encoding,
+    };
+
+    self.request(msg, recv).await
+  }
+
+  /// Counts the tokens of the template, with special tokens treated as normal text, after
+  /// replacing every `{{key}}` placeholder with `substitutions[key]`. Fails if a placeholder has
+  /// no substitution.
+  #[napi]
+  pub async fn num_tokens_template(
+    &self,
+    template: String,
+    substitutions: HashMap<String, String>,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let text = fill_template(&template, |key| substitutions.get(key).map(String::as_str)).map_err(
+      |key| {
+        TokenizerErrorKind::InvalidArgument.error(format!("No substitution for {{{{{}}}}}", key))
+      },
+    )?;
+    self.exact_num_tokens_no_special_tokens(text, encoding).await
+  }
+
+  /// Counts the tokens of the template with every placeholder left out, i.e. the fixed cost that
+  /// every filled in version of it shares, up to a token or two where text meets a substitution.
+  #[napi]
+  pub async fn num_tokens_template_static(
+    &self,
+    template: String,
+    encoding: SupportedEncoding,
+  ) -> Result<i32, Error> {
+    let text = fill_template(&template, |_| Some("")).unwrap_or_default();
+    self.exact_num_tokens_no_special_tokens(text, encoding).await
+  }
+
+  /// Like `encode` with special tokens treated as normal text, but every call with the same
+  /// `affinity_key` is handled by the same actor, whose caches are then warm for text it has seen
+  /// before, like a long shared prompt prefix. The message waits for that actor even when others
+  /// are idle, so spread unrelated work over different keys.
+  #[napi]
+  pub async fn encode_with_affinity(
+    &self,
+    text: String,
+    encoding: SupportedEncoding,
+    affinity_key: u32,
+  ) -> Result<Vec<u32>, Error> {
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::EncodeTokens {
+      respond_to: send,
+      text,
+      encoding,
+      special_token_handling: tiktoken::SpecialTokenHandling {
+        default: tiktoken::SpecialTokenAction::NormalText,
+        ..Default::default()
+      },
+      strip_control_chars: false,
+      max_special_tokens: None,
+    };
+
+    self.request_with_affinity(msg, recv, affinity_key).await
+  }
+
+  /// Counts the tokens of the text with every built-in encoding in a single actor message, keyed
+  /// by encoding name. Special tokens are treated as normal text.
+  #[napi]
+  pub async fn num_tokens_all_encodings(
+    &self,
+    text: String,
+  ) -> Result<HashMap<String, i32>, Error> {
+    // the actors expect every encoding they use to be loaded already
+    for encoding in SupportedEncoding::ALL {
+      self.get_encoding(encoding)?;
+    }
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::NumTokensAll { respond_to: send, text };
+
+    self.request(msg, recv).await.map(|counts| {
+      counts
+        .into_iter()
+        .map(|(encoding, num_tokens)| (encoding.name().to_string(), num_tokens as i32))
+        .collect()
+    })
+  }
+
+  /// The number of BPE merges encoding the text takes, with special tokens treated as normal text.
+  /// Every merge joins two parts into one, starting from one part per byte, so this is exactly the
+  /// number of bytes minus the number of tokens, without instrumenting the BPE loop.
+  #[napi]
+  pub async fn num_merges(&self, text: String, encoding: SupportedEncoding) -> Result<i32, Error> {
+    let num_bytes = text.len() as i32;
+    let num_tokens = self.exact_num_tokens_no_special_tokens(text, encoding).await?;
+    Ok(num_bytes - num_tokens)
+  }
+}
+
+/// Cancels the `Tokenizer.encodeCancellable` calls it is passed to.
+#[napi]
+#[derive(Default)]
+pub struct CancellationToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+#[napi]
+impl CancellationToken {
+  #[napi(constructor)]
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  #[napi]
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::Relaxed);
+  }
+
+  #[napi(getter)]
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::Relaxed)
+  }
+}
+
+/// Decodes tokens one at a time as a model emits them, without decoding the whole output again
+/// for every token. Runs on the calling thread.
+#[napi]
+pub struct StreamDecoder {
+  encodings: Arc<Encodings>,
+  encoding: SupportedEncoding,
+  // bytes of a character that isn't complete yet
+  pending: Vec<u8>,
+}
+
+#[napi]
+impl StreamDecoder {
+  #[napi(constructor)]
+  pub fn new(encoding: SupportedEncoding) -> Result<Self, Error> {
+    let decoder = Self { encodings: ENCODINGS.clone(), encoding, pending: vec![] };
+    decoder.get_encoding()?;
+    Ok(decoder)
+  }
+
+  /// Adds the token and returns the text it completes. A character split across tokens is held
+  /// back until its last token arrives, so the returned pieces concatenate to the full output.
+  #[napi]
+  pub fn push(&mut self, token: u32) -> Result<String, Error> {
+    let bytes = self
+      .get_encoding()?
+      .decode_single_token_bytes(token as usize)
+      .map_err(|e| TokenizerErrorKind::UnknownToken.error(e))?;
+    self.pending.extend_from_slice(&bytes);
+    Ok(take_complete_utf8(&mut self.pending))
+  }
+
+  /// Returns the rest of a character that was never completed, as U+FFFD, and resets the decoder.
+  #[napi]
+  pub fn finish(&mut self) -> String {
+    let rest = String::from_utf8_lossy(&self.pending).into_owned();
+    self.pending.clear();
+    rest
+  }
+
+  fn get_encoding(&self) -> Result<&tiktoken::Encoding, Error> {
+    self
+      .encodings
+      .get(self.encoding)
+      .map_err(|e| {
+        TokenizerErrorKind::EncodingError.error(format!(
+          "Failed to load {}: {}",
+          self.encoding.name(),
+          e
+        ))
+      })?
+      .ok_or_else(|| {
+        TokenizerErrorKind::InvalidArgument
+          .error("StreamDecoder does not support the custom encoding")
+      })
+  }
+}
+
+#[napi]
+pub struct SyncTokenizer {
+  encodings: Arc<Encodings>,
 }
 
 #[napi]
-impl Tokenizer {
-  pub fn new() -> Result<Self, tiktoken::EncodingFactoryError> {
-    let (sender, receiver) = bounded(256);
-    for i in 0..4 {
-      let actor = TokenizerActor::new(receiver.clone(), ENCODINGS.clone().unwrap());
-      std::thread::Builder::new()
-        .name(format!("tokenizer-actor-{}", i))
-        .spawn(move || run_tokenizer_actor(actor))
-        .unwrap();
-    }
+impl SyncTokenizer {
+  #[napi(constructor)]
+  pub fn new() -> Result<Self, napi::Error> {
+    Ok(Self { encodings: ENCODINGS.clone() })
+  }
 
-    Ok(Self { sender })
+  /// Like the constructor, but loads every built-in encoding up front and throws if one of them
+  /// fails to load, e.g. because its vocab file is missing, instead of failing on first use.
+  #[napi(factory)]
+  pub fn try_new() -> Result<Self, Error> {
+    preload_encodings()?;
+    Ok(Self { encodings: ENCODINGS.clone() })
   }
 
   #[napi]
-  pub async fn exact_num_tokens_no_special_tokens(
-    &self,
-    text: String,
-    encoding: SupportedEncoding,
-  ) -> Result<i32, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg = TokenizerMessage::ExactNumTokens {
-      respond_to: send,
-      text,
-      encoding,
-      special_token_handling: tiktoken::SpecialTokenHandling {
-        // no special tokens!! everything is normal text
-        // this is how tokenization is handled in the chat model api
-        default: tiktoken::SpecialTokenAction::NormalText,
-        ..Default::default()
-      },
-    };
-
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result.map_err(|e| Error::from_reason(e.to_string())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
-    }
+  pub fn approx_num_tokens(&self, text: String, encoding: SupportedEncoding) -> Result<i32, Error> {
+    Ok(self.get_encoding(encoding)?.estimate_num_tokens_no_special_tokens_fast(&text, false) as i32)
   }
 
+  /// Like `Tokenizer.encode`, but runs on the calling thread instead of the actor pool.
   #[napi]
-  pub async fn exact_num_tokens(
+  pub fn encode(
     &self,
     text: String,
     encoding: SupportedEncoding,
     special_token_default_action: SpecialTokenAction,
     special_token_overrides: HashMap<String, SpecialTokenAction>,
-  ) -> Result<i32, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg = TokenizerMessage::ExactNumTokens {
-      respond_to: send,
-      text,
-      encoding,
-      special_token_handling: tiktoken::SpecialTokenHandling {
-        // no special tokens!! everything is normal text
-        // this is how tokenization is handled in the chat model api
-        default: special_token_default_action.to_tiktoken(),
-        overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
-      },
+    ignore_unknown_overrides: Option<bool>,
+  ) -> Result<Vec<u32>, Error> {
+    check_special_token_overrides(
+      self.get_encoding(encoding)?,
+      &special_token_overrides,
+      ignore_unknown_overrides.unwrap_or(false),
+    )?;
+    let special_token_handling = tiktoken::SpecialTokenHandling {
+      default: special_token_default_action.to_tiktoken(),
+      overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
     };
+    self
+      .get_encoding(encoding)?
+      .encode(&text, &special_token_handling)
+      .context("Error encoding string")
+      .map(|t| t.into_iter().map(|t| t as u32).collect())
+      .map_err(|e| TokenizerErrorKind::EncodingError.error(e))
+  }
 
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result.map_err(|e| Error::from_reason(e.to_string())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
-    }
+  /// Like `Tokenizer.decode`, but runs on the calling thread instead of the actor pool.
+  #[napi]
+  pub fn decode(&self, tokens: Vec<u32>, encoding: SupportedEncoding) -> Result<String, Error> {
+    Ok(
+      self
+        .get_encoding(encoding)?
+        .decode(&tokens.into_iter().map(|t| t as usize).collect::<Vec<_>>()),
+    )
   }
 
+  /// The number of tokens in the encoding, including the special tokens.
   #[napi]
-  pub async fn encode_cl100k_no_special_tokens(&self, text: String) -> Result<Vec<u32>, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg = TokenizerMessage::EncodeTokens {
-      respond_to: send,
-      text,
-      encoding: SupportedEncoding::Cl100k,
-      special_token_handling: tiktoken::SpecialTokenHandling {
-        // no special tokens!! everything is normal text
-        // this is how tokenization is handled in the chat model api
-        default: tiktoken::SpecialTokenAction::NormalText,
-        ..Default::default()
-      },
-    };
+  pub fn vocab_size(&self, encoding: SupportedEncoding) -> Result<u32, Error> {
+    Ok(vocab_size(self.get_encoding(encoding)?))
+  }
 
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result.map_err(|e| Error::from_reason(e.to_string())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
+  /// The special token strings of the encoding, sorted.
+  #[napi]
+  pub fn special_tokens(&self, encoding: SupportedEncoding) -> Result<Vec<String>, Error> {
+    Ok(special_tokens(self.get_encoding(encoding)?))
+  }
+
+  /// Whether the token id is one of the encoding's special tokens.
+  #[napi]
+  pub fn is_special_token(&self, token: u32, encoding: SupportedEncoding) -> Result<bool, Error> {
+    Ok(self.get_encoding(encoding)?.special_tokens.values().any(|&t| t == token as usize))
+  }
+
+  /// The token's bytes as a string, with invalid UTF-8 replaced by U+FFFD. Never throws: tokens the
+  /// encoding doesn't know, and encodings this tokenizer can't use, give `"<invalid>"`.
+  #[napi]
+  pub fn token_to_string_lossy(&self, token: u32, encoding: SupportedEncoding) -> String {
+    match self.get_encoding(encoding).map(|e| e.decode_single_token_bytes(token as usize)) {
+      Ok(Ok(bytes)) if !bytes.is_empty() => String::from_utf8_lossy(&bytes).into_owned(),
+      _ => "<invalid>".to_string(),
     }
   }
 
+  /// Like `Tokenizer.exact_num_tokens`, but runs on the calling thread instead of the actor pool.
   #[napi]
-  pub async fn approx_num_tokens(
+  pub fn exact_num_tokens(
     &self,
     text: String,
     encoding: SupportedEncoding,
-    replace_spaces_with_lower_one_eighth_block: bool,
+    special_token_default_action: SpecialTokenAction,
+    special_token_overrides: HashMap<String, SpecialTokenAction>,
+    ignore_unknown_overrides: Option<bool>,
   ) -> Result<i32, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg = TokenizerMessage::ApproximateNumTokens {
-      respond_to: send,
-      text,
-      encoding,
-      replace_spaces_with_lower_one_eighth_block,
-    };
-
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result.map_err(|e| Error::from_reason(e.to_string())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
-    }
+    self
+      .encode(
+        text,
+        encoding,
+        special_token_default_action,
+        special_token_overrides,
+        ignore_unknown_overrides,
+      )
+      .map(|tokens| tokens.len() as i32)
   }
 
+  /// Like `approx_num_tokens`, with the same `replace_spaces_with_lower_one_eighth_block` flag as
+  /// `Tokenizer.approx_num_tokens`, so both give the same estimate.
   #[napi]
-  pub async fn encode(
+  pub fn approx_num_tokens_sync(
     &self,
     text: String,
     encoding: SupportedEncoding,
-    special_token_default_action: SpecialTokenAction,
-    special_token_overrides: HashMap<String, SpecialTokenAction>,
-  ) -> Result<Vec<u32>, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg = TokenizerMessage::EncodeTokens {
-      respond_to: send,
-      text,
-      encoding,
-      special_token_handling: tiktoken::SpecialTokenHandling {
-        // no special tokens!! everything is normal text
-        // this is how tokenization is handled in the chat model api
-        default: special_token_default_action.to_tiktoken(),
-        overrides: special_token_overrides.into_iter().map(|(k, v)| (k, v.to_tiktoken())).collect(),
-      },
-    };
+    replace_spaces_with_lower_one_eighth_block: bool,
+  ) -> Result<i32, Error> {
+    Ok(
+      self.get_encoding(encoding)?.estimate_num_tokens_no_special_tokens_fast(
+        &text,
+        replace_spaces_with_lower_one_eighth_block,
+      ) as i32,
+    )
+  }
 
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result.map_err(|e| Error::from_reason(e.to_string())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
-    }
+  /// The BPE merge rank of the token, which for tiktoken encodings is the token id itself. Fails
+  /// for special tokens, which are not merged, and for unknown tokens.
+  #[napi]
+  pub fn token_rank(&self, token: u32, encoding: SupportedEncoding) -> Result<u32, Error> {
+    let encoding = self.get_encoding(encoding)?;
+    let bytes = encoding
+      .decode_single_token_bytes(token as usize)
+      .map_err(|e| TokenizerErrorKind::UnknownToken.error(e))?;
+    encoding.mergeable_ranks.get(&bytes).map(|&rank| rank as u32).ok_or_else(|| {
+      TokenizerErrorKind::InvalidArgument
+        .error(format!("Token {} is a special token and has no rank", token))
+    })
   }
 
+  /// The number of bytes the token decodes to.
   #[napi]
-  pub async fn encode_single_token(
+  pub fn byte_length_of_token(
     &self,
-    bytes: napi::bindgen_prelude::Uint8Array,
+    token: u32,
     encoding: SupportedEncoding,
   ) -> Result<u32, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg =
-      TokenizerMessage::EncodeSingleToken { respond_to: send, bytes: bytes.to_vec(), encoding };
+    self
+      .get_encoding(encoding)?
+      .decode_single_token_bytes(token as usize)
+      .map(|bytes| bytes.len() as u32)
+      .map_err(|e| TokenizerErrorKind::UnknownToken.error(e))
+  }
 
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result.map_err(|e| Error::from_reason(e.to_string())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
+  /// Guesses which of the candidate encodings produced the tokens, by counting the tokens each one
+  /// knows and decodes to valid UTF-8 on its own. Ties go to the earlier candidate. This is a
+  /// heuristic: encodings that share most of their vocabulary, like cl100k and o200k for common
+  /// English, can't be told apart from a short sample.
+  #[napi]
+  pub fn guess_encoding(
+    &self,
+    sample_tokens: Vec<u32>,
+    candidates: Vec<SupportedEncoding>,
+  ) -> Result<SupportedEncoding, Error> {
+    let mut best: Option<(SupportedEncoding, usize)> = None;
+    for candidate in candidates {
+      let encoding = self.get_encoding(candidate)?;
+      let score = sample_tokens
+        .iter()
+        .filter(|&&t| {
+          encoding
+            .decode_single_token_bytes(t as usize)
+            .is_ok_and(|bytes| std::str::from_utf8(&bytes).is_ok())
+        })
+        .count();
+      if !best.is_some_and(|(_, best_score)| best_score >= score) {
+        best = Some((candidate, score));
+      }
     }
+    best
+      .map(|(candidate, _)| candidate)
+      .ok_or_else(|| TokenizerErrorKind::InvalidArgument.error("No candidate encodings given"))
   }
+
+  /// Splits the text into chunks of roughly `target_tokens` tokens each, going by the fast
+  /// estimate instead of running BPE, so it is much cheaper than exact chunking but chunks can be
+  /// well off the target. Chunks end after whitespace where possible and concatenate to the text.
   #[napi]
-  pub async fn decode_byte(
+  pub fn approx_chunk_by_tokens(
     &self,
-    token: u32,
+    text: String,
+    target_tokens: u32,
     encoding: SupportedEncoding,
-  ) -> Result<napi::bindgen_prelude::Uint8Array, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg = TokenizerMessage::DecodeTokenBytes { respond_to: send, token, encoding };
-
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result
-        .map_err(|e| napi::Error::from_reason(e.to_string()))
-        .map(|v| napi::bindgen_prelude::Uint8Array::new(v.into())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
+  ) -> Result<Vec<String>, Error> {
+    if target_tokens == 0 {
+      return Err(TokenizerErrorKind::InvalidArgument.error("target_tokens must be at least 1"));
+    }
+    let estimate =
+      self.get_encoding(encoding)?.estimate_num_tokens_no_special_tokens_fast(&text, false);
+    if estimate == 0 {
+      return Ok(if text.is_empty() { vec![] } else { vec![text] });
     }
+    let bytes_per_token = text.len() as f64 / estimate as f64;
+    let target_bytes = (target_tokens as f64 * bytes_per_token).round() as usize;
+    Ok(split_near_whitespace(&text, target_bytes).into_iter().map(String::from).collect())
   }
 
+  /// The length in bytes of the encoding's longest token, including the special tokens, so no
+  /// single token decodes to more than this. Computed on the first call for each encoding.
   #[napi]
-  pub async fn decode(
+  pub fn max_token_byte_length(&self, encoding: SupportedEncoding) -> Result<u32, Error> {
+    let loaded = self.get_encoding(encoding)?;
+    Ok(self.encodings.lazy(encoding).map_or_else(
+      || max_token_byte_length(loaded),
+      |lazy| *lazy.max_token_byte_length.get_or_init(|| max_token_byte_length(loaded)),
+    ))
+  }
+
+  /// Whether the text literally contains one of the encoding's special tokens, like
+  /// `<|endoftext|>`. Only scans for the strings, without encoding the text.
+  #[napi]
+  pub fn contains_special_literal(
     &self,
-    encoded_tokens: Vec<u32>,
+    text: String,
     encoding: SupportedEncoding,
-  ) -> Result<String, Error> {
-    let (send, recv) = oneshot::channel();
-    let msg = TokenizerMessage::DecodeTokens { respond_to: send, tokens: encoded_tokens, encoding };
+  ) -> Result<bool, Error> {
+    Ok(
+      self.get_encoding(encoding)?.special_tokens.keys().any(|token| text.contains(token.as_str())),
+    )
+  }
 
-    // ignore errors since it can only mean the channel is closed, which will be caught in the recv below
-    let _ = self.sender.send(msg).await;
-    match recv.await {
-      Ok(result) => result.map_err(|e| Error::from_reason(e.to_string())),
-      Err(e) => Err(Error::from_reason(format!("Actor task has been killed: {}", e.to_string()))),
-    }
+  fn get_encoding(&self, encoding: SupportedEncoding) -> Result<&tiktoken::Encoding, Error> {
+    self
+      .encodings
+      .get(encoding)
+      .map_err(|e| {
+        TokenizerErrorKind::EncodingError.error(format!(
+          "Failed to load {}: {}",
+          encoding.name(),
+          e
+        ))
+      })?
+      .ok_or_else(|| {
+        TokenizerErrorKind::InvalidArgument
+          .error("SyncTokenizer has no custom encoding, use Tokenizer.fromTiktokenFile")
+      })
   }
 }
 
 #[napi]
-pub struct SyncTokenizer {
-  encodings: Arc<Encodings>,
+pub fn get_tokenizer() -> Result<Tokenizer, Error> {
+  TOKENIZER.clone()
 }
 
+// model name prefixes, matched longest first so that e.g. gpt-4o doesn't resolve as gpt-4
+const MODEL_PREFIX_ENCODINGS: &[(&str, SupportedEncoding)] = &[
+  ("gpt-5", SupportedEncoding::O200k),
+  ("gpt-4.5", SupportedEncoding::O200k),
+  ("gpt-4.1", SupportedEncoding::O200k),
+  ("gpt-4o", SupportedEncoding::O200k),
+  ("chatgpt-4o", SupportedEncoding::O200k),
+  ("o1", SupportedEncoding::O200k),
+  ("o3", SupportedEncoding::O200k),
+  ("o4", SupportedEncoding::O200k),
+  ("gpt-4", SupportedEncoding::Cl100k),
+  ("gpt-3.5-turbo", SupportedEncoding::Cl100k),
+  ("gpt-35-turbo", SupportedEncoding::Cl100k),
+  ("text-embedding-ada-002", SupportedEncoding::Cl100k),
+  ("text-embedding-3", SupportedEncoding::Cl100k),
+  ("llama-3", SupportedEncoding::Llama3),
+  ("llama3", SupportedEncoding::Llama3),
+  ("meta-llama-3", SupportedEncoding::Llama3),
+  ("codestral", SupportedEncoding::Codestral),
+  ("text-davinci-003", SupportedEncoding::P50k),
+  ("text-davinci-002", SupportedEncoding::P50k),
+  ("code-davinci", SupportedEncoding::P50k),
+  ("code-cushman", SupportedEncoding::P50k),
+  ("text-davinci-001", SupportedEncoding::R50k),
+  ("text-curie", SupportedEncoding::R50k),
+  ("text-babbage", SupportedEncoding::R50k),
+  ("text-ada", SupportedEncoding::R50k),
+  ("davinci", SupportedEncoding::R50k),
+  ("curie", SupportedEncoding::R50k),
+  ("babbage", SupportedEncoding::R50k),
+  ("ada", SupportedEncoding::R50k),
+  ("gpt2", SupportedEncoding::Gpt2),
+];
+
+/// Resolves a model name like `gpt-4o-mini-2024-07-18` to its encoding by longest prefix match.
 #[napi]
-impl SyncTokenizer {
-  #[napi(constructor)]
-  pub fn new() -> Result<Self, napi::Error> {
-    Ok(Self { encodings: ENCODINGS.clone().unwrap() })
-  }
+pub fn encoding_for_model(model: String) -> Result<SupportedEncoding, Error> {
+  MODEL_PREFIX_ENCODINGS
+    .iter()
+    .filter(|(prefix, _)| model.starts_with(prefix))
+    .max_by_key(|(prefix, _)| prefix.len())
+    .map(|(_, encoding)| *encoding)
+    .ok_or_else(|| TokenizerErrorKind::InvalidArgument.error(format!("Unknown model: {}", model)))
+}
 
-  #[napi]
-  pub fn approx_num_tokens(&self, text: String, encoding: SupportedEncoding) -> Result<i32, Error> {
-    Ok(self.get_encoding(encoding).estimate_num_tokens_no_special_tokens_fast(&text, false) as i32)
-  }
+// context window sizes in tokens by model name prefix, matched longest first like the encodings
+const MODEL_PREFIX_CONTEXT_WINDOWS: &[(&str, u32)] = &[
+  ("gpt-5", 400_000),
+  ("gpt-4.5", 128_000),
+  ("gpt-4.1", 1_047_576),
+  ("gpt-4o", 128_000),
+  ("chatgpt-4o", 128_000),
+  ("o1", 200_000),
+  ("o1-mini", 128_000),
+  ("o1-preview", 128_000),
+  ("o3", 200_000),
+  ("o4", 200_000),
+  ("gpt-4-turbo", 128_000),
+  ("gpt-4-1106", 128_000),
+  ("gpt-4-0125", 128_000),
+  ("gpt-4-32k", 32_768),
+  ("gpt-4", 8_192),
+  ("gpt-3.5-turbo", 16_385),
+  ("gpt-3.5-turbo-instruct", 4_096),
+  ("text-embedding-ada-002", 8_191),
+  ("text-embedding-3", 8_191),
+  ("llama-3", 8_192),
+  ("llama-3.1", 131_072),
+  ("llama-3.2", 131_072),
+  ("llama-3.3", 131_072),
+  ("llama3", 8_192),
+  ("llama3.1", 131_072),
+  ("llama3.2", 131_072),
+  ("llama3.3", 131_072),
+  ("meta-llama-3", 8_192),
+  ("meta-llama-3.1", 131_072),
+  ("meta-llama-3.2", 131_072),
+  ("meta-llama-3.3", 131_072),
+  ("codestral", 32_768),
+  ("text-davinci-003", 4_097),
+  ("text-davinci-002", 4_097),
+  ("code-davinci", 8_001),
+  ("code-cushman", 2_048),
+  ("text-davinci-001", 2_049),
+  ("text-curie", 2_049),
+  ("text-babbage", 2_049),
+  ("text-ada", 2_049),
+  ("davinci", 2_049),
+  ("curie", 2_049),
+  ("babbage", 2_049),
+  ("ada", 2_049),
+  ("gpt2", 1_024),
+];
 
-  fn get_encoding(&self, encoding: SupportedEncoding) -> &tiktoken::Encoding {
-    match encoding {
-      SupportedEncoding::Cl100k => &self.encodings.cl100k_encoding,
-      SupportedEncoding::Llama3 => &self.encodings.llama3_encoding,
-      SupportedEncoding::O200k => &self.encodings.o200k_encoding,
-      SupportedEncoding::Codestral => &self.encodings.codestral_encoding,
-    }
+fn context_window_for_model(model: &str) -> Option<u32> {
+  MODEL_PREFIX_CONTEXT_WINDOWS
+    .iter()
+    .filter(|(prefix, _)| model.starts_with(prefix))
+    .max_by_key(|(prefix, _)| prefix.len())
+    .map(|(_, context_window)| *context_window)
+}
+
+/// Whether the given encoding is available in this build. Every encoding is currently compiled in,
+/// so this only returns false if loading the encoding failed. Loads the encoding if needed.
+#[napi]
+pub fn has_encoding(encoding: SupportedEncoding) -> bool {
+  match encoding {
+    SupportedEncoding::Cl100k
+    | SupportedEncoding::Llama3
+    | SupportedEncoding::O200k
+    | SupportedEncoding::Codestral
+    | SupportedEncoding::R50k
+    | SupportedEncoding::P50k
+    | SupportedEncoding::Gpt2 => ENCODINGS.get(encoding).is_ok(),
+    SupportedEncoding::Custom => false,
   }
 }
 
+/// Loads the built-in encodings if that hasn't happened yet, so the first real request doesn't pay
+/// for it, and returns how long each one took to load. Later calls return the same timings.
 #[napi]
-pub fn get_tokenizer() -> Result<Tokenizer, Error> {
-  TOKENIZER.clone()
+pub fn preload_encodings() -> Result<LoadTimings, Error> {
+  ENCODINGS.load_timings().map_err(|e| {
+    TokenizerErrorKind::EncodingError.error(format!("Failed to load encodings: {}", e))
+  })
+}
+
+/// Loads just the given built-in encodings if they aren't loaded yet, so the first requests using
+/// them don't pay for it. Safe to call repeatedly.
+#[napi]
+pub fn preload(encodings: Vec<SupportedEncoding>) -> Result<(), Error> {
+  for encoding in encodings {
+    match ENCODINGS.get(encoding) {
+      Ok(Some(_)) => {}
+      Ok(None) => {
+        return Err(TokenizerErrorKind::InvalidArgument.error(
+          "The custom encoding is loaded by Tokenizer.fromTiktokenFile and can't be preloaded",
+        ))
+      }
+      Err(e) => {
+        return Err(TokenizerErrorKind::EncodingError.error(format!(
+          "Failed to load {}: {}",
+          encoding.name(),
+          e
+        )))
+      }
+    }
+  }
+  Ok(())
 }
 
 #[allow(clippy::expect_used)]
@@ -461,4 +4804,377 @@ mod tests {
       .unwrap();
     assert_eq!(num_tokens, 3);
   }
+
+  #[test]
+  fn test_strip_c0_control_chars() {
+    assert_eq!(strip_c0_control_chars("a\u{0}b\tc\r\nd\u{1b}e\u{7f}"), "ab\tc\nde\u{7f}");
+  }
+
+  #[test]
+  fn test_changed_lines_of_unified_diff() {
+    let diff =
+      "--- a/foo.rs\n+++ b/foo.rs\n@@ -1,3 +1,3 @@\n fn main() {\n-  old();\n+  new();\n }\n";
+    assert_eq!(changed_lines_of_unified_diff(diff).unwrap(), "  old();\n  new();\n");
+    assert!(changed_lines_of_unified_diff("@@ -1,2 +1,2 @@\n-a\n").is_err());
+    assert!(changed_lines_of_unified_diff("@@ bogus @@\n-a\n").is_err());
+    assert_eq!(changed_lines_of_unified_diff("").unwrap(), "");
+    assert!(changed_lines_of_unified_diff("just some prose\n").is_err());
+    assert!(changed_lines_of_unified_diff("--- a/foo.rs\n+++ b/foo.rs\n").is_err());
+  }
+
+  #[test]
+  fn test_split_sentences() {
+    assert_eq!(
+      split_sentences("Hi there. How are you?! Fine.\nBye"),
+      vec!["Hi there.", " How are you?!", " Fine.", "\nBye"]
+    );
+    assert_eq!(split_sentences("v1.2 is out."), vec!["v1.2 is out."]);
+  }
+
+  #[tokio::test]
+  async fn test_pinned_token_counts() {
+    // A tiktoken upgrade that changes the counts of the built-in encodings fails this test instead
+    // of silently shifting everyone's budgets.
+    let pangram = "The quick brown fox jumps over the lazy dog.";
+    let cases = [
+      (SupportedEncoding::Cl100k, "", 0),
+      (SupportedEncoding::Cl100k, "hello world", 2),
+      (SupportedEncoding::Cl100k, pangram, 10),
+      (SupportedEncoding::Cl100k, "<|endoftext|>", 7),
+      (SupportedEncoding::O200k, "", 0),
+      (SupportedEncoding::O200k, "hello world", 2),
+      (SupportedEncoding::O200k, pangram, 10),
+      (SupportedEncoding::Llama3, "", 0),
+      (SupportedEncoding::Llama3, "hello world", 2),
+      (SupportedEncoding::Llama3, pangram, 10),
+      (SupportedEncoding::Codestral, "", 0),
+      (SupportedEncoding::R50k, "", 0),
+      (SupportedEncoding::R50k, "hello world", 2),
+      (SupportedEncoding::R50k, pangram, 10),
+      (SupportedEncoding::R50k, "<|endoftext|>", 7),
+      (SupportedEncoding::P50k, "", 0),
+      (SupportedEncoding::P50k, "hello world", 2),
+      (SupportedEncoding::P50k, pangram, 10),
+      (SupportedEncoding::P50k, "<|endoftext|>", 7),
+      (SupportedEncoding::Gpt2, "", 0),
+      (SupportedEncoding::Gpt2, "hello world", 2),
+      (SupportedEncoding::Gpt2, pangram, 10),
+      (SupportedEncoding::Gpt2, "<|endoftext|>", 7),
+    ];
+    let tokenizer = get_tokenizer().unwrap();
+    for (encoding, text, expected) in cases {
+      let num_tokens =
+        tokenizer.exact_num_tokens_no_special_tokens(text.to_string(), encoding).await.unwrap();
+      assert_eq!(num_tokens, expected, "{} tokens of {:?}", encoding.name(), text);
+    }
+  }
+
+  #[test]
+  fn test_encoding_for_model() {
+    assert!(matches!(
+      encoding_for_model("gpt-4o-mini-2024-07-18".to_string()),
+      Ok(SupportedEncoding::O200k)
+    ));
+    assert!(matches!(encoding_for_model("gpt-4-0314".to_string()), Ok(SupportedEncoding::Cl100k)));
+    assert!(encoding_for_model("claude-3-opus".to_string()).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_num_tokens_for_chat() {
+    let tokenizer = get_tokenizer().unwrap();
+    let messages = vec![ChatMessage {
+      role: "user".to_string(),
+      content: "hello world".to_string(),
+      name: None,
+    }];
+    let num_tokens =
+      tokenizer.num_tokens_for_chat(messages, SupportedEncoding::Cl100k).await.unwrap();
+    // 3 framing + 1 role + 2 content + 3 reply priming
+    assert_eq!(num_tokens, 9);
+  }
+
+  #[tokio::test]
+  async fn test_truncate_to_tokens() {
+    let tokenizer = get_tokenizer().unwrap();
+    let text = "The quick brown fox jumps over the lazy dog.".to_string();
+    let head = tokenizer
+      .truncate_to_tokens(text.clone(), 3, SupportedEncoding::Cl100k, false)
+      .await
+      .unwrap();
+    assert_eq!(head, "The quick brown");
+    let tail =
+      tokenizer.truncate_to_tokens(text.clone(), 2, SupportedEncoding::Cl100k, true).await.unwrap();
+    assert_eq!(tail, " dog.");
+    let all =
+      tokenizer.truncate_to_tokens(text.clone(), 100, SupportedEncoding::Cl100k, false).await;
+    assert_eq!(all.unwrap(), text);
+  }
+
+  #[tokio::test]
+  async fn test_chunk_by_tokens() {
+    let tokenizer = get_tokenizer().unwrap();
+    let text = "The quick brown fox jumps over the lazy dog.".to_string();
+    let chunks = tokenizer.chunk_by_tokens(text, 4, 1, SupportedEncoding::Cl100k).await.unwrap();
+    assert_eq!(chunks, vec!["The quick brown fox", " fox jumps over the", " the lazy dog."]);
+    assert!(tokenizer
+      .chunk_by_tokens("hello".to_string(), 2, 2, SupportedEncoding::Cl100k)
+      .await
+      .is_err());
+  }
+
+  #[test]
+  fn test_parse_tiktoken_ranks() {
+    let ranks = parse_tiktoken_ranks("YQ== 0\nYg== 1\n").unwrap();
+    assert_eq!(ranks.get(b"a".as_slice()), Some(&0));
+    assert_eq!(ranks.get(b"b".as_slice()), Some(&1));
+    let err = parse_tiktoken_ranks("YQ== 0\nYg== 0\n").unwrap_err();
+    assert_eq!(err.to_string(), "Line 2: duplicate rank 0");
+    assert!(parse_tiktoken_ranks("!!! 0\n").is_err());
+  }
+
+  #[test]
+  fn test_encode_chunks() {
+    let text = "first line\nsecond line\n  indented\nthird";
+    assert_eq!(encode_chunks(text, 8), vec!["first line\n", "second line\n  indented\n", "third"]);
+    // without a newline in the window it splits before a space instead
+    assert_eq!(
+      encode_chunks(text, 4),
+      vec!["first", " line\n", "second", " line\n  indented\n", "third"]
+    );
+    assert_eq!(encode_chunks("aaaa bbbb  cccc dddd", 4), vec!["aaaa", " bbbb", "  cccc", " dddd"]);
+    assert_eq!(encode_chunks(text, 1000), vec![text]);
+    // byte 4 is inside "本"
+    assert_eq!(encode_chunks("日本語\nabc", 4), vec!["日本語\n", "abc"]);
+    let text = format!("{}\nabc", "日本語".repeat(10));
+    assert_eq!(encode_chunks(&text, 16), vec![&text[..91], "abc"]);
+  }
+
+  #[test]
+  fn test_pretokenize() {
+    let gpt2_pattern = fancy_regex::Regex::new(
+      r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+",
+    )
+    .unwrap();
+    assert_eq!(
+      pretokenize(&gpt2_pattern, "Hello  world's 42!\n").unwrap(),
+      vec!["Hello", " ", " world", "'s", " 42", "!", "\n"]
+    );
+    // text the pattern doesn't match is kept
+    let regex = fancy_regex::Regex::new("a+").unwrap();
+    assert_eq!(pretokenize(&regex, "xaay").unwrap(), vec!["x", "aa", "y"]);
+  }
+
+  #[test]
+  fn test_utf16_offsets() {
+    // "é" is 2 bytes and 1 unit, "😀" is 4 bytes and 2 units
+    let text = "aé😀b";
+    assert_eq!(utf16_offsets(text, [0, 1, 3, 3, 7, 8]), vec![0, 1, 2, 2, 4, 5]);
+  }
+
+  #[test]
+  fn test_last_sentence_end() {
+    assert_eq!(last_sentence_end("One. Two! Three", 15), Some(9));
+    assert_eq!(last_sentence_end("Version 1.2 is out", 18), None);
+    // the cut right after the last period keeps that sentence
+    assert_eq!(last_sentence_end("First. Second. Third", 14), Some(14));
+    assert_eq!(last_sentence_end("Done.", 5), Some(5));
+    // a terminator followed by more text in the original is not a sentence end
+    assert_eq!(last_sentence_end("Version 1.2 is out", 10), None);
+  }
+
+  #[test]
+  fn test_remove_all() {
+    let remove = vec!["a".to_string(), "".to_string(), "[ad]".to_string()];
+    assert_eq!(remove_all("x[ad]ya".to_string(), remove), "xy");
+  }
+
+  #[test]
+  fn test_take_complete_utf8() {
+    // "é" is 0xC3 0xA9
+    let mut pending = vec![b'a', 0xC3];
+    assert_eq!(take_complete_utf8(&mut pending), "a");
+    assert_eq!(pending, vec![0xC3]);
+    pending.push(0xA9);
+    assert_eq!(take_complete_utf8(&mut pending), "é");
+    assert!(pending.is_empty());
+
+    let mut pending = vec![0xFF, b'b'];
+    assert_eq!(take_complete_utf8(&mut pending), "\u{FFFD}b");
+  }
+
+  #[test]
+  fn test_pack_greedily() {
+    let counts = || [3, 2, 4, 1].into_iter().map(Ok);
+    // 3, then 1 + 2, then 1 + 4 doesn't fit
+    assert_eq!(pack_greedily(counts(), 1, 9).unwrap(), (2, 6));
+    assert_eq!(pack_greedily(counts(), 1, 11).unwrap(), (3, 11));
+    assert_eq!(pack_greedily(counts(), 0, 2).unwrap(), (0, 0));
+  }
+
+  #[test]
+  fn test_batch_stats() {
+    let stats = batch_stats(vec![3, 0, 9]);
+    assert_eq!((stats.total, stats.max, stats.min, stats.mean), (12, 9, 0, 4.0));
+    assert_eq!(stats.counts, vec![3, 0, 9]);
+
+    let stats = batch_stats(vec![]);
+    assert_eq!((stats.total, stats.max, stats.min, stats.mean), (0, 0, 0, 0.0));
+  }
+
+  #[test]
+  fn test_split_near_whitespace() {
+    assert_eq!(split_near_whitespace("aaa bbb ccc ddd", 8), vec!["aaa bbb ", "ccc ddd"]);
+    // the nearer whitespace wins, even if it is past the target
+    assert_eq!(split_near_whitespace("a aaaa bbbb", 5), vec!["a aaaa ", "bbbb"]);
+    assert_eq!(split_near_whitespace("aaaaaaaaaa", 4), vec!["aaaa", "aaaa", "aa"]);
+    assert_eq!(split_near_whitespace("ééé", 1), vec!["é", "é", "é"]);
+    assert!(split_near_whitespace("", 4).is_empty());
+  }
+
+  #[test]
+  fn test_fill_template() {
+    let values = HashMap::from([("name".to_string(), "Ada".to_string())]);
+    let value = |key: &str| values.get(key).map(String::as_str);
+    assert_eq!(fill_template("Hi {{name}}, {{ name }}!", value).unwrap(), "Hi Ada, Ada!");
+    assert_eq!(fill_template("{{name}} {{", value).unwrap(), "Ada {{");
+    assert_eq!(fill_template("{{missing}}", value).unwrap_err(), "missing");
+    assert_eq!(fill_template("a{{x}}b", |_| Some("")).unwrap(), "ab");
+  }
+
+  #[test]
+  fn test_utf8_lossy_with() {
+    let bytes = b"a\xffb\xe2\x82";
+    assert_eq!(utf8_lossy_with(bytes, "\u{FFFD}"), String::from_utf8_lossy(bytes));
+    assert_eq!(utf8_lossy_with(bytes, "?"), "a?b?");
+    assert_eq!(utf8_lossy_with(bytes, ""), "ab");
+    assert_eq!(utf8_lossy_with("é".as_bytes(), ""), "é");
+  }
+
+  #[tokio::test]
+  async fn test_decode_checked_looks_up_every_token() {
+    let tokenizer = get_tokenizer().unwrap();
+    // <|endofprompt|> is past the number of tokens, 100256 is a gap in the ids
+    let text =
+      tokenizer.decode_checked(vec![15339, 100276], SupportedEncoding::Cl100k).await.unwrap();
+    assert_eq!(text, "hello<|endofprompt|>");
+    let err = tokenizer.decode_checked(vec![15339, 100256], SupportedEncoding::Cl100k).await;
+    assert!(err.unwrap_err().reason.contains("Token 100256 at index 1"));
+  }
+
+  #[test]
+  fn test_sync_encode_rejects_unknown_overrides() {
+    let tokenizer = SyncTokenizer::new().unwrap();
+    let overrides =
+      || HashMap::from([("<|not_a_special_token|>".to_string(), SpecialTokenAction::Special)]);
+    let err = tokenizer
+      .exact_num_tokens(
+        "hello".to_string(),
+        SupportedEncoding::Cl100k,
+        SpecialTokenAction::NormalText,
+        overrides(),
+        None,
+      )
+      .unwrap_err();
+    assert!(err.reason.contains("<|not_a_special_token|>"));
+
+    let num_tokens = tokenizer
+      .exact_num_tokens(
+        "hello".to_string(),
+        SupportedEncoding::Cl100k,
+        SpecialTokenAction::NormalText,
+        overrides(),
+        Some(true),
+      )
+      .unwrap();
+    assert_eq!(num_tokens, 1);
+  }
+
+  #[tokio::test]
+  async fn test_split_tokens_by_sentence_stays_under_the_limit() {
+    let tokenizer = get_tokenizer().unwrap();
+    // the second sentence alone is over the limit and gets split on token boundaries
+    let long = "antidisestablishmentarianism ".repeat(10);
+    let text = format!("Short one. {}end. Tail.", long);
+    let chunks =
+      tokenizer.split_tokens_by_sentence(text.clone(), SupportedEncoding::Cl100k, 8).await.unwrap();
+    assert!(chunks.len() > 2);
+    assert!(chunks.iter().all(|chunk| chunk.num_tokens <= 8));
+    assert_eq!(chunks.iter().map(|chunk| chunk.text.as_str()).collect::<String>(), text);
+  }
+
+  #[tokio::test]
+  async fn test_actor_health_counts_restarts() {
+    let tokenizer = Tokenizer::with_config(1, None).unwrap();
+    let health = tokenizer.actor_health();
+    assert_eq!((health.len(), health[0].running, health[0].restarts), (1, true, 0));
+
+    // a custom encoding message on a tokenizer without one skips `request`'s check and panics
+    let (send, recv) = oneshot::channel();
+    let msg = TokenizerMessage::TokensPerChar {
+      respond_to: send,
+      text: "hello".to_string(),
+      encoding: SupportedEncoding::Custom,
+    };
+    tokenizer.sender.send(msg).await.unwrap();
+    let err = Tokenizer::response(recv).await.unwrap_err();
+    assert!(err.reason.starts_with("ActorDead"));
+
+    let health = tokenizer.actor_health();
+    assert_eq!((health[0].running, health[0].restarts), (true, 1));
+    tokenizer.close().await.unwrap();
+    assert!(tokenizer.actor_health().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_num_tokens_at_least() {
+    let tokenizer = get_tokenizer().unwrap();
+    let encoding = SupportedEncoding::Cl100k;
+    // "hello world" is 2 tokens
+    let text = "hello world".to_string();
+    assert!(tokenizer.num_tokens_at_least(text.clone(), encoding, 2).await.unwrap());
+    assert!(!tokenizer.num_tokens_at_least(text, encoding, 3).await.unwrap());
+    assert!(tokenizer.num_tokens_at_least(String::new(), encoding, 0).await.unwrap());
+    // a single long line is chunked before spaces, so this stops early
+    let long = "word ".repeat(100_000);
+    assert!(tokenizer.num_tokens_at_least(long, encoding, 1000).await.unwrap());
+  }
+
+  #[test]
+  fn test_context_window_for_model() {
+    assert_eq!(context_window_for_model("gpt-4-0314"), Some(8_192));
+    assert_eq!(context_window_for_model("gpt-4o-2024-08-06"), Some(128_000));
+    // the longest prefix wins, so later llama 3 releases don't get the 8k of the first one
+    assert_eq!(context_window_for_model("llama-3-8b"), Some(8_192));
+    assert_eq!(context_window_for_model("llama-3.2-3b-instruct"), Some(131_072));
+    assert_eq!(context_window_for_model("llama3.3-70b"), Some(131_072));
+    assert_eq!(context_window_for_model("meta-llama-3.2-1b"), Some(131_072));
+    assert_eq!(context_window_for_model("meta-llama-3.3-70b-instruct"), Some(131_072));
+    assert_eq!(context_window_for_model("claude-3-opus"), None);
+  }
+
+  #[tokio::test]
+  async fn test_approx_num_tokens_with_bounds() {
+    let tokenizer = get_tokenizer().unwrap();
+    let samples = [
+      "The quick brown fox jumps over the lazy dog.",
+      "fn main() {\n    let xs: Vec<u32> = (0..10).collect();\n    println!(\"{:?}\", xs);\n}\n",
+      "日本語のテキスト、Ελληνικά και русский текст.",
+    ];
+    for encoding in [SupportedEncoding::Cl100k, SupportedEncoding::O200k] {
+      for text in samples {
+        let bounds =
+          tokenizer.approx_num_tokens_with_bounds(text.to_string(), encoding, false).await.unwrap();
+        let exact =
+          tokenizer.exact_num_tokens_no_special_tokens(text.to_string(), encoding).await.unwrap();
+        assert!(
+          (bounds.lower..=bounds.upper).contains(&exact),
+          "{} tokens of {:?} outside {}..={}",
+          exact,
+          text,
+          bounds.lower,
+          bounds.upper
+        );
+      }
+    }
+  }
 }